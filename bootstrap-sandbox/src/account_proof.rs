@@ -0,0 +1,114 @@
+//! Find an account in a ledger and prove that the ledger includes it, the proof is
+//! checked with [`verify`].
+
+use mina_signer::CompressedPubKey;
+use mina_tree::{Account, TokenId};
+pub use openmina_bootstrap_sandbox::merkle_path::{verify, PathEntry, DEPTH};
+
+use super::snarked_ledger::{Error, LedgerView};
+
+/// The index and the account with this public key and token, the default token if `None`.
+/// Reads the accounts one by one, the ledger may be on disk.
+pub fn find<L>(
+    ledger: &mut L,
+    public_key: &CompressedPubKey,
+    token_id: Option<&TokenId>,
+) -> Result<Option<(u64, Account)>, Error>
+where
+    L: LedgerView + ?Sized,
+{
+    let token_id = token_id.cloned().unwrap_or_default();
+    for index in 0..(ledger.num_accounts() as u64) {
+        if let Some(account) = ledger.account_at(index)? {
+            if account.public_key == *public_key && account.token_id == token_id {
+                return Ok(Some((index, account)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The path of the account at this index, from the account up to the root.
+pub fn path<L>(ledger: &mut L, index: u64) -> Result<Vec<PathEntry>, Error>
+where
+    L: LedgerView + ?Sized,
+{
+    let mut pos = index;
+    let mut path = Vec::with_capacity(DEPTH as usize);
+    for depth in (1..=DEPTH).rev() {
+        let sibling = ledger.hash_at(depth, pos ^ 1)?;
+        path.push(if pos % 2 == 0 {
+            PathEntry::Left(sibling)
+        } else {
+            PathEntry::Right(sibling)
+        });
+        pos /= 2;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_curves::pasta::Fp;
+    use mina_tree::{scan_state::currency::Balance, AccountIndex};
+
+    use super::{super::snarked_ledger::SnarkedLedger, *};
+
+    #[test]
+    fn find_and_verify() {
+        let public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+        let other = CompressedPubKey {
+            x: public_key.x,
+            is_odd: !public_key.is_odd,
+        };
+        let mut ledger = SnarkedLedger::empty();
+        for i in 0..11 {
+            let mut account = Account::empty();
+            account.public_key = if i == 6 {
+                public_key.clone()
+            } else {
+                other.clone()
+            };
+            account.token_id = TokenId(Fp::from(i + 2));
+            if i == 6 {
+                account.token_id = TokenId::default();
+            }
+            account.balance = Balance::of_nanomina_int_exn(1000 + i);
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i), Box::new(account))
+                .unwrap();
+        }
+        ledger.num = 11;
+        let root = ledger.hash_at(0, 0).unwrap();
+
+        let (index, account) = find(&mut ledger, &public_key, None).unwrap().unwrap();
+        assert_eq!(index, 6);
+        let other_token = TokenId(Fp::from(5u64));
+        assert_eq!(
+            find(&mut ledger, &other, Some(&other_token))
+                .unwrap()
+                .unwrap()
+                .0,
+            3
+        );
+        assert!(find(&mut ledger, &public_key, Some(&other_token))
+            .unwrap()
+            .is_none());
+
+        let proof = path(&mut ledger, index).unwrap();
+        assert!(verify(&account, &proof, &root));
+
+        let mut changed = account.clone();
+        changed.balance = Balance::of_nanomina_int_exn(1);
+        assert!(!verify(&changed, &proof, &root));
+        let (_, neighbour) = find(&mut ledger, &other, Some(&TokenId(Fp::from(9u64))))
+            .unwrap()
+            .unwrap();
+        assert!(!verify(&neighbour, &proof, &root));
+        assert!(!verify(&account, &proof[1..], &root));
+    }
+}
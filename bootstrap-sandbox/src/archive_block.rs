@@ -1,4 +1,4 @@
-use std::{path::Path, fs::File, io::Write, time::Duration, borrow::Cow};
+use std::{path::Path, fs::File, io::Write, time::Duration};
 
 use binprot::BinProtRead;
 use mina_signer::CompressedPubKey;
@@ -16,6 +16,7 @@ use mina_tree::{
             ProtocolStateView, EpochData as UntypedEpochData, EpochLedger as UntypedEpochLedger,
         },
         protocol_state::hashes_abstract,
+        scan_state::ConstraintConstants,
         self,
     },
 };
@@ -120,137 +121,37 @@ impl ProtocolState {
 #[cfg(test)]
 #[test]
 fn ar() {
-    run("docker/archive");
+    let constraint_constants = mina_transport::network::Network::berkeley()
+        .constraint_constants
+        .to_ledger()
+        .unwrap();
+    run("docker/archive", &constraint_constants);
 }
 
-pub fn run<P>(path: P)
+pub fn run<P>(path: P, constraint_constants: &ConstraintConstants)
 where
     P: AsRef<Path>,
 {
-    use super::bootstrap::CONSTRAINT_CONSTANTS;
-
-    let accounts = |ledger: serde_json::Value| -> Option<Vec<Account>> {
-        let it = ledger
-            .as_object()?
-            .get("ledger")?
-            .as_object()?
-            .get("accounts")?
-            .as_array()?
-            .iter();
-        let mut hashes = vec![];
-        let mut list: Vec<_> = it
-            .cloned()
-            .map(|mut a| {
-                use mina_tree::{
-                    scan_state::currency::{SlotSpan, Balance},
-                    Timing,
-                };
-
-                let account_value = a.as_object_mut().unwrap();
-
-                let mut account = Account::empty();
-                account.public_key = CompressedPubKey::from_address(
-                    account_value
-                        .remove("pk")
-                        .unwrap()
-                        .clone()
-                        .as_str()
-                        .unwrap(),
-                )
-                .unwrap();
-                if let Some(balance) = account_value.remove("balance") {
-                    let balance = balance.as_str().unwrap();
-                    let balance = if !balance.contains('.') {
-                        Cow::Owned(format!("{balance}.000000000"))
-                    } else {
-                        Cow::Borrowed(balance)
-                    };
-                    account.balance = Balance::of_mina_string_exn(&balance);
-                }
-                if let Some(delegate) = account_value
-                    .remove("delegate")
-                    .and_then(|a| a.as_str().map(ToOwned::to_owned))
-                {
-                    account.delegate = Some(CompressedPubKey::from_address(&delegate).unwrap());
-                } else {
-                    account.delegate = Some(account.public_key.clone());
-                }
-                if let Some(timing) = account_value.remove("timing") {
-                    #[derive(Deserialize, Debug)]
-                    struct Timed {
-                        initial_minimum_balance: String,
-                        cliff_time: [String; 2],
-                        cliff_amount: String,
-                        vesting_period: [String; 2],
-                        vesting_increment: String,
-                    }
-
-                    let Timed {
-                        mut initial_minimum_balance,
-                        cliff_time,
-                        mut cliff_amount,
-                        vesting_period,
-                        mut vesting_increment,
-                    } = serde_json::from_value(timing.clone()).unwrap();
-
-                    if !initial_minimum_balance.contains('.') {
-                        initial_minimum_balance.extend(".000000000".chars());
-                    }
-                    if !cliff_amount.contains('.') {
-                        cliff_amount.extend(".000000000".chars());
-                    }
-                    if !vesting_increment.contains('.') {
-                        vesting_increment.extend(".000000000".chars());
-                    }
-
-                    account.timing = Timing::Timed {
-                        initial_minimum_balance: Balance::of_mina_string_exn(
-                            &initial_minimum_balance,
-                        ),
-                        cliff_time: Slot::from_u32(cliff_time[1].parse().unwrap()),
-                        cliff_amount: Balance::of_mina_string_exn(&cliff_amount).to_amount(),
-                        vesting_period: SlotSpan::from_u32(vesting_period[1].parse().unwrap()),
-                        vesting_increment: Balance::of_mina_string_exn(&vesting_increment)
-                            .to_amount(),
-                    };
-                }
-                account_value.remove("sk");
-
-                hashes.push(
-                    v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(account.hash().into()))
-                        .to_string(),
-                );
-
-                assert!(account_value.is_empty());
-
-                account
-            })
-            .collect();
-
-        // println!("{}", serde_json::to_string(&hashes).unwrap());
-
-        list.insert(0, {
-            let mut account = Account::empty();
-            account.public_key = CompressedPubKey::from_address(
-                "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
-            )
-            .unwrap();
-
-            account.balance = mina_tree::scan_state::currency::Balance::of_nanomina_int_exn(1000);
-            account.delegate = Some(account.public_key.clone());
-            // println!(
-            //     "{}",
-            //     v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(account.hash().into()))
-            // );
-            account
-        });
-
-        Some(list)
-    };
+    use super::runtime_config;
 
     let ledger_file = File::open(path.as_ref().join("ledger.json")).unwrap();
     let value = serde_json::from_reader::<_, serde_json::Value>(ledger_file).unwrap();
-    let accounts = accounts(value).unwrap();
+    let mut accounts = runtime_config::import(&value).unwrap();
+    accounts.insert(0, {
+        let mut account = Account::empty();
+        account.public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+
+        account.balance = mina_tree::scan_state::currency::Balance::of_nanomina_int_exn(1000);
+        account.delegate = Some(account.public_key.clone());
+        // println!(
+        //     "{}",
+        //     v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(account.hash().into()))
+        // );
+        account
+    });
 
     let mut inner = Mask::new_root(Database::create(35));
     for account in accounts {
@@ -268,7 +169,7 @@ where
         v2::StateHash::from(v2::DataHashLibStateHashStableV1(root.into()))
     );
 
-    let mut staged_ledger = StagedLedger::create_exn(CONSTRAINT_CONSTANTS, inner).unwrap();
+    let mut staged_ledger = StagedLedger::create_exn(constraint_constants.clone(), inner).unwrap();
 
     let block_file = File::open(path.as_ref().join("blocks/1.json")).unwrap();
     let block = serde_json::from_reader::<_, Block>(block_file).unwrap();
@@ -296,7 +197,7 @@ where
     // let result = staged_ledger
     //     .apply(
     //         None,
-    //         &CONSTRAINT_CONSTANTS,
+    //         constraint_constants,
     //         (&v2::MinaNumbersGlobalSlotSinceGenesisMStableV1::SinceGenesis(0.into())).into(),
     //         Diff::empty(),
     //         (),
@@ -334,7 +235,7 @@ where
 
     let (diff, _) = staged_ledger
         .create_diff(
-            &CONSTRAINT_CONSTANTS,
+            constraint_constants,
             (&v2::MinaNumbersGlobalSlotSinceGenesisMStableV1::SinceGenesis(1u32.into())).into(),
             None,
             (&coinbase_receiver).into(),
@@ -354,7 +255,7 @@ where
     let result = staged_ledger
         .apply(
             None,
-            &CONSTRAINT_CONSTANTS,
+            constraint_constants,
             (&v2::MinaNumbersGlobalSlotSinceGenesisMStableV1::SinceGenesis(1.into())).into(),
             (&block_2_p2p.body.staged_ledger_diff).into(),
             (),
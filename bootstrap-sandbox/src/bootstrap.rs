@@ -1,4 +1,10 @@
-use std::{collections::BTreeMap, fs::File, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    io,
+    path::Path,
+};
 
 use binprot::BinProtRead;
 use mina_p2p_messages::{
@@ -7,34 +13,44 @@ use mina_p2p_messages::{
     rpc_kernel::RpcMethod,
 };
 use mina_tree::{
+    Account, AccountId, BaseLedger, Timing, TokenId,
     mask::Mask,
     staged_ledger::{staged_ledger::StagedLedger, diff::Diff},
     verifier::Verifier,
     scan_state::{
         scan_state::ConstraintConstants,
-        currency::{Amount, Fee},
         transaction_logic::{local_state::LocalState, protocol_state},
         self,
     },
 };
 use mina_signer::CompressedPubKey;
+use serde::Serialize;
+use thiserror::Error;
 
-use super::snarked_ledger::SnarkedLedger;
-
-pub const CONSTRAINT_CONSTANTS: ConstraintConstants = ConstraintConstants {
-    sub_windows_per_window: 11,
-    ledger_depth: 35,
-    work_delay: 2,
-    block_window_duration_ms: 180000,
-    transaction_capacity_log_2: 7,
-    pending_coinbase_depth: 5,
-    coinbase_amount: Amount::from_u64(720000000000),
-    supercharged_coinbase_factor: 2,
-    account_creation_fee: Fee::from_u64(1000000000),
-    fork: None,
+use super::{
+    snarked_ledger::{self, LedgerView, SnarkedLedger},
+    snapshot,
+    ledger_store::LedgerStore,
+    runtime_config,
 };
 
-pub async fn again(path_main: &Path, height: u32) {
+pub async fn again(
+    path_main: &Path,
+    height: u32,
+    ledger_store: Option<&LedgerStore>,
+    constraint_constants: &ConstraintConstants,
+) {
+    apply_recorded(path_main, height, ledger_store, constraint_constants);
+}
+
+/// Apply the recorded blocks of this height on top of the snarked ledger, returns
+/// whether the coinbase of each block was supercharged.
+pub fn apply_recorded(
+    path_main: &Path,
+    height: u32,
+    ledger_store: Option<&LedgerStore>,
+    constraint_constants: &ConstraintConstants,
+) -> Vec<bool> {
     let path_blocks = path_main.join("blocks");
     let path = path_main.join(height.to_string());
 
@@ -58,9 +74,19 @@ pub async fn again(path_main: &Path, height: u32) {
         serde_json::Value::String(s) => s,
         _ => panic!(),
     };
-    let snarked_ledger = match File::open(path.join("ledgers").join(snarked_ledger_hash_str)) {
-        Ok(file) => SnarkedLedger::load_bin(file).unwrap(),
-        Err(_) => SnarkedLedger::empty(),
+    // the staged ledger is built on top of the snarked ledger in memory, the other ledgers
+    // are read from disk
+    let stored = ledger_store.and_then(|store| store.ledger(&snarked_ledger_hash_str).unwrap());
+    let snarked_ledger = match stored {
+        Some(ledger) => Ok(ledger.to_ledger().unwrap()),
+        None => snapshot::load(path.join("ledgers").join(snarked_ledger_hash_str)),
+    };
+    let snarked_ledger = match snarked_ledger {
+        Ok(ledger) => ledger,
+        Err(snapshot::Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            SnarkedLedger::empty()
+        }
+        Err(err) => panic!("{err}"),
     };
 
     let mut file = File::open(path.join("staged_ledger_aux")).unwrap();
@@ -72,7 +98,13 @@ pub async fn again(path_main: &Path, height: u32) {
         .blockchain_state
         .staged_ledger_hash
         .clone();
-    let mut storage = Storage::new(snarked_ledger.inner, info, expected_hash);
+    let mut storage = Storage::new(
+        constraint_constants.clone(),
+        snarked_ledger.inner,
+        info,
+        expected_hash,
+    )
+    .unwrap_or_else(|err| panic!("{err}"));
 
     let file = File::open(path_main.join("blocks").join("table.json")).unwrap();
     let table = serde_json::from_reader::<_, BTreeMap<String, u32>>(file).unwrap();
@@ -90,23 +122,278 @@ pub async fn again(path_main: &Path, height: u32) {
         blocks.push(new);
     }
 
+    // the winners of the slots are in the staking ledgers, and the expected accounts
+    // are in the ledgers after each block, if any of them is recorded
+    let known_hashes = blocks
+        .iter()
+        .flat_map(|block| {
+            let body = &block.header.protocol_state.body;
+            [
+                body.consensus_state
+                    .staking_epoch_data
+                    .ledger
+                    .hash
+                    .to_string(),
+                body.blockchain_state
+                    .staged_ledger_hash
+                    .non_snark
+                    .ledger_hash
+                    .to_string(),
+            ]
+        })
+        .collect::<BTreeSet<_>>();
+    for hash in known_hashes {
+        let stored = ledger_store
+            .and_then(|store| store.ledger(&hash).unwrap())
+            .map(|ledger| Box::new(ledger) as Box<dyn LedgerView>);
+        let ledger = stored.or_else(|| snapshot::open(path.join("ledgers").join(&hash)).ok());
+        if let Some(ledger) = ledger {
+            storage = storage.with_ledger(hash, ledger);
+        }
+    }
+
+    let mut supercharged = vec![];
     let mut last_protocol_state = last_protocol_state;
     while let Some(block) = blocks.pop() {
-        storage.apply_block(&block, &last_protocol_state);
+        let result = storage.apply_block(&block, &last_protocol_state);
+        supercharged.push(result.unwrap_or_else(|err| panic!("{err}")));
         last_protocol_state = block.header.protocol_state.clone();
     }
+    supercharged
+}
+
+/// The minimum balance of the account at the slot, only the tokens above it are liquid.
+fn min_balance_at_slot(timing: &Timing, global_slot: u32) -> u64 {
+    let Timing::Timed {
+        initial_minimum_balance,
+        cliff_time,
+        cliff_amount,
+        vesting_period,
+        vesting_increment,
+    } = timing
+    else {
+        return 0;
+    };
+    let initial_minimum_balance = initial_minimum_balance.as_u64();
+    if global_slot < cliff_time.as_u32() {
+        initial_minimum_balance
+    } else if vesting_period.as_u32() == 0 {
+        0
+    } else {
+        let past_cliff = initial_minimum_balance.saturating_sub(cliff_amount.as_u64());
+        let periods = (global_slot - cliff_time.as_u32()) / vesting_period.as_u32();
+        past_cliff.saturating_sub((periods as u64).saturating_mul(vesting_increment.as_u64()))
+    }
+}
+
+/// As the protocol decides it: the coinbase is supercharged if the winner of the slot,
+/// the account in the staking ledger, has no locked tokens at the global slot of the block.
+pub fn supercharge_coinbase(winner: &Account, global_slot: u32) -> bool {
+    min_balance_at_slot(&winner.timing, global_slot) == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashPart {
+    LedgerHash,
+    AuxHash,
+    PendingCoinbaseAux,
+    PendingCoinbaseHash,
+}
+
+impl fmt::Display for HashPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashPart::LedgerHash => write!(f, "ledger hash"),
+            HashPart::AuxHash => write!(f, "aux hash"),
+            HashPart::PendingCoinbaseAux => write!(f, "pending coinbase aux"),
+            HashPart::PendingCoinbaseHash => write!(f, "pending coinbase hash"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PartMismatch {
+    pub part: HashPart,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// An account the block touched, in the format of [`runtime_config::export_account`],
+/// `None` if there is no such account.
+#[derive(Debug)]
+pub struct TouchedAccount {
+    pub public_key: String,
+    pub token_id: String,
+    pub before: Option<serde_json::Value>,
+    pub actual: Option<serde_json::Value>,
+    /// Known only if the ledger with the expected hash is given to the storage, it is the
+    /// staged ledger after the block. A recording has only the snarked and the epoch
+    /// ledgers, the peers do not serve the staged ones, so it is usually unknown.
+    pub expected: Option<serde_json::Value>,
+}
+
+/// The staged ledger hash differs from the expected one, only the differing parts are
+/// listed. The accounts are listed if the ledger hash differs and there is a block.
+#[derive(Debug)]
+pub struct HashMismatch {
+    pub parts: Vec<PartMismatch>,
+    pub accounts: Vec<TouchedAccount>,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "staged ledger hash mismatch")?;
+        for PartMismatch {
+            part,
+            expected,
+            actual,
+        } in &self.parts
+        {
+            writeln!(f, "  {part}: expected {expected}, actual {actual}")?;
+        }
+        if !self.accounts.is_empty() {
+            writeln!(f, "  touched accounts:")?;
+        }
+        let show = |account: &Option<serde_json::Value>| match account {
+            Some(account) => account.to_string(),
+            None => "none".to_owned(),
+        };
+        for account in &self.accounts {
+            writeln!(f, "    {}, token {}", account.public_key, account.token_id)?;
+            writeln!(f, "      before: {}", show(&account.before))?;
+            writeln!(f, "      actual: {}", show(&account.actual))?;
+            match &account.expected {
+                Some(expected) => writeln!(f, "      expected: {expected}")?,
+                None => writeln!(f, "      expected: unknown")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+#[derive(Debug, Error)]
+pub enum ApplyError {
+    #[error("{0}")]
+    HashMismatch(#[from] HashMismatch),
+    #[error("the staking ledger {0} is not recorded, cannot find the winner of the slot")]
+    UnknownStakingLedger(String),
+    #[error("the winner of the slot {winner} is not in the staking ledger {ledger}")]
+    UnknownWinner { winner: String, ledger: String },
+    #[error("block {length}: supercharge coinbase is {actual}, the block says {expected}")]
+    SuperchargeMismatch {
+        length: u32,
+        actual: bool,
+        expected: bool,
+    },
+    #[error("{0}")]
+    Ledger(#[from] snarked_ledger::Error),
+}
+
+/// Whether the coinbase of the block is supercharged, the winner of the slot is looked
+/// up in the staking ledger of the block among the `ledgers` by hash.
+pub fn supercharge_of_block(
+    ledgers: &mut BTreeMap<String, Box<dyn LedgerView>>,
+    staking_ledger: &str,
+    winner: &CompressedPubKey,
+    global_slot: u32,
+) -> Result<bool, ApplyError> {
+    let ledger = ledgers
+        .get_mut(staking_ledger)
+        .ok_or_else(|| ApplyError::UnknownStakingLedger(staking_ledger.to_owned()))?;
+    let id = AccountId::new(winner.clone(), TokenId::default());
+    match ledger.account_of(&id)? {
+        Some(winner) => Ok(supercharge_coinbase(&winner, global_slot)),
+        None => Err(ApplyError::UnknownWinner {
+            winner: winner.into_address(),
+            ledger: staking_ledger.to_owned(),
+        }),
+    }
+}
+
+fn hash_parts(
+    expected: &v2::MinaBaseStagedLedgerHashStableV1,
+    actual: &v2::MinaBaseStagedLedgerHashStableV1,
+) -> Vec<PartMismatch> {
+    fn show<T: Serialize>(value: &T) -> String {
+        match serde_json::to_value(value) {
+            Ok(serde_json::Value::String(s)) => s,
+            Ok(value) => value.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    let (e, a) = (&expected.non_snark, &actual.non_snark);
+    let parts = [
+        (
+            HashPart::LedgerHash,
+            show(&e.ledger_hash),
+            show(&a.ledger_hash),
+        ),
+        (HashPart::AuxHash, show(&e.aux_hash), show(&a.aux_hash)),
+        (
+            HashPart::PendingCoinbaseAux,
+            show(&e.pending_coinbase_aux),
+            show(&a.pending_coinbase_aux),
+        ),
+        (
+            HashPart::PendingCoinbaseHash,
+            show(&expected.pending_coinbase_hash),
+            show(&actual.pending_coinbase_hash),
+        ),
+    ];
+    parts
+        .into_iter()
+        .filter(|(_, expected, actual)| expected != actual)
+        .map(|(part, expected, actual)| PartMismatch {
+            part,
+            expected,
+            actual,
+        })
+        .collect()
+}
+
+/// The coinbase receiver, the snark workers and every account the commands refer to.
+fn touched_accounts(diff: &Diff, coinbase_receiver: &CompressedPubKey) -> Vec<AccountId> {
+    let mut ids = vec![AccountId::new(
+        coinbase_receiver.clone(),
+        TokenId::default(),
+    )];
+    for work in diff.completed_works() {
+        ids.push(AccountId::new(work.prover.clone(), TokenId::default()));
+    }
+    for command in diff.commands() {
+        ids.extend(command.data.accounts_referenced());
+    }
+    let mut unique = Vec::with_capacity(ids.len());
+    for id in ids {
+        if !unique.contains(&id) {
+            unique.push(id);
+        }
+    }
+    unique
+}
+
+fn account_of(ledger: &Mask, id: &AccountId) -> Option<Account> {
+    let addr = ledger.location_of_account(id)?;
+    ledger.get(addr).map(|account| *account)
 }
 
 pub struct Storage {
+    constraint_constants: ConstraintConstants,
     staged_ledger: StagedLedger,
+    /// By ledger hash, not loaded.
+    known_ledgers: BTreeMap<String, Box<dyn LedgerView>>,
 }
 
 impl Storage {
     pub fn new(
+        constraint_constants: ConstraintConstants,
         snarked_ledger: Mask,
         info: GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response,
         expected_hash: v2::MinaBaseStagedLedgerHashStableV1,
-    ) -> Self {
+    ) -> Result<Self, HashMismatch> {
         let (scan_state, expected_ledger_hash, pending_coinbase, states) = info.unwrap();
 
         let states = states
@@ -116,7 +403,7 @@ impl Storage {
 
         let mut staged_ledger = StagedLedger::of_scan_state_pending_coinbases_and_snarked_ledger(
             (),
-            &CONSTRAINT_CONSTANTS,
+            &constraint_constants,
             Verifier,
             (&scan_state).into(),
             snarked_ledger.clone(),
@@ -134,16 +421,35 @@ impl Storage {
         let actual_hash_str = serde_json::to_string(&actual_hash).unwrap();
         log::info!("actual staged ledger hash {actual_hash_str}");
 
-        assert_eq!(expected_hash, actual_hash);
+        let parts = hash_parts(&expected_hash, &actual_hash);
+        if !parts.is_empty() {
+            return Err(HashMismatch {
+                parts,
+                accounts: vec![],
+            });
+        }
 
-        Storage { staged_ledger }
+        Ok(Storage {
+            constraint_constants,
+            staged_ledger,
+            known_ledgers: BTreeMap::new(),
+        })
     }
 
+    /// A ledger that some blocks refer to. The winners of the slots are looked up in the
+    /// staking ledger of the epoch, it must be given for every block. The expected accounts
+    /// of a hash mismatch are looked up in the ledger with the expected hash.
+    pub fn with_ledger(mut self, hash: String, ledger: Box<dyn LedgerView>) -> Self {
+        self.known_ledgers.insert(hash, ledger);
+        self
+    }
+
+    /// Returns whether the coinbase was supercharged.
     pub fn apply_block(
         &mut self,
         block: &v2::MinaBlockBlockStableV2,
         prev_protocol_state: &v2::MinaStateProtocolStateValueStableV2,
-    ) {
+    ) -> Result<bool, ApplyError> {
         let length = block
             .header
             .protocol_state
@@ -187,20 +493,33 @@ impl Storage {
         let protocol_state = &block.header.protocol_state;
         let consensus_state = &protocol_state.body.consensus_state;
         let coinbase_receiver: CompressedPubKey = (&consensus_state.coinbase_receiver).into();
-        let _supercharge_coinbase = consensus_state.supercharge_coinbase;
-
-        dbg!(&coinbase_receiver, _supercharge_coinbase);
-
-        // FIXME: Using `supercharge_coinbase` (from block) above does not work
-        let supercharge_coinbase = false;
+        let supercharge_coinbase = supercharge_of_block(
+            &mut self.known_ledgers,
+            &consensus_state.staking_epoch_data.ledger.hash.to_string(),
+            &(&consensus_state.block_stake_winner).into(),
+            global_slot.as_u32(),
+        )?;
+        if supercharge_coinbase != consensus_state.supercharge_coinbase {
+            return Err(ApplyError::SuperchargeMismatch {
+                length,
+                actual: supercharge_coinbase,
+                expected: consensus_state.supercharge_coinbase,
+            });
+        }
 
         let diff: Diff = (&block.body.staged_ledger_diff).into();
+        let touched = touched_accounts(&diff, &coinbase_receiver);
+        let ledger = self.staged_ledger.ledger();
+        let before = touched
+            .iter()
+            .map(|id| account_of(&ledger, id))
+            .collect::<Vec<_>>();
 
         let result = self
             .staged_ledger
             .apply(
                 None,
-                &CONSTRAINT_CONSTANTS,
+                &self.constraint_constants,
                 (&global_slot).into(),
                 diff,
                 (),
@@ -214,16 +533,258 @@ impl Storage {
         let hash = v2::MinaBaseStagedLedgerHashStableV1::from(&result.hash_after_applying);
         let hash_str = serde_json::to_string(&hash).unwrap();
         log::info!("new staged ledger hash {hash_str}");
-        let expected_hash_str = serde_json::to_string(
-            &block
-                .header
-                .protocol_state
-                .body
-                .blockchain_state
-                .staged_ledger_hash,
+        let expected_hash = &protocol_state.body.blockchain_state.staged_ledger_hash;
+        let expected_hash_str = serde_json::to_string(expected_hash).unwrap();
+        log::info!("expected staged ledger hash {expected_hash_str}");
+
+        let parts = hash_parts(expected_hash, &hash);
+        if parts.is_empty() {
+            return Ok(supercharge_coinbase);
+        }
+        let mut accounts = vec![];
+        if parts.iter().any(|p| p.part == HashPart::LedgerHash) {
+            let actual = self.staged_ledger.ledger();
+            let expected_ledger_hash = expected_hash.non_snark.ledger_hash.to_string();
+            let mut expected = self.known_ledgers.get_mut(&expected_ledger_hash);
+            for (id, before) in touched.into_iter().zip(before) {
+                let json = |account: Option<Account>| {
+                    account.map(|account| runtime_config::export_account(&account))
+                };
+                let token_id = v2::TokenIdKeyHash::from(v2::MinaBaseAccountIdDigestStableV1(
+                    id.token_id.0.into(),
+                ));
+                accounts.push(TouchedAccount {
+                    public_key: id.public_key.into_address(),
+                    token_id: token_id.to_string(),
+                    before: json(before),
+                    actual: json(account_of(&actual, &id)),
+                    expected: json(
+                        expected
+                            .as_mut()
+                            .and_then(|ledger| ledger.account_of(&id).unwrap()),
+                    ),
+                });
+            }
+        }
+        Err(HashMismatch { parts, accounts }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_curves::pasta::{Fp, Fq};
+    use mina_signer::Signature;
+    use mina_tree::{
+        AccountIndex, Database,
+        scan_state::{
+            currency::{Amount, Balance, Fee, Nonce, Slot, SlotSpan},
+            transaction_logic::{
+                Memo, TransactionStatus, UserCommand, WithStatus,
+                signed_command::{
+                    Body, Common, PaymentPayload, SignedCommand, SignedCommandPayload,
+                },
+            },
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn mismatch_report() {
+        let mut account = Account::empty();
+        account.public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
         )
         .unwrap();
-        log::info!("expected staged ledger hash {expected_hash_str}");
-        assert_eq!(hash_str, expected_hash_str);
+        let before = runtime_config::export_account(&account);
+        account.balance = Balance::of_nanomina_int_exn(1000);
+        let actual = runtime_config::export_account(&account);
+        let err = HashMismatch {
+            parts: vec![PartMismatch {
+                part: HashPart::LedgerHash,
+                expected: "jx1".to_owned(),
+                actual: "jx2".to_owned(),
+            }],
+            accounts: vec![TouchedAccount {
+                public_key: account.public_key.into_address(),
+                token_id: "wSHV2S4qX9jFsLjQo8r1BsMLH2ZRKsZx6EJd1sbozGPieEC4Jf".to_owned(),
+                before: Some(before.clone()),
+                actual: Some(actual),
+                expected: None,
+            }],
+        };
+        let report = err.to_string();
+        assert!(report.contains("ledger hash: expected jx1, actual jx2"));
+        assert!(!report.contains("aux hash"));
+        assert!(report.contains(&format!("before: {before}")));
+        assert!(report.contains("expected: unknown"));
+    }
+
+    fn ledger_hash(mask: &Mask) -> v2::LedgerHash {
+        v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(mask.merkle_root().into()))
+    }
+
+    #[test]
+    fn mismatch_parts() {
+        let constraint_constants = mina_transport::network::Network::berkeley()
+            .constraint_constants
+            .to_ledger()
+            .unwrap();
+        let mask = Mask::new_root(Database::create(35));
+        let staged_ledger = StagedLedger::create_exn(constraint_constants, mask).unwrap();
+        let expected = v2::MinaBaseStagedLedgerHashStableV1::from(&staged_ledger.hash());
+        assert!(hash_parts(&expected, &expected.clone()).is_empty());
+
+        let mut other = Mask::new_root(Database::create(35));
+        let mut account = Account::empty();
+        account.balance = Balance::of_nanomina_int_exn(1000);
+        other
+            .set_at_index(AccountIndex(0), Box::new(account))
+            .unwrap();
+        let mut actual = expected.clone();
+        actual.non_snark.ledger_hash = ledger_hash(&other);
+
+        let parts = hash_parts(&expected, &actual);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].part, HashPart::LedgerHash);
+        let show = |hash: &v2::LedgerHash| match serde_json::to_value(hash).unwrap() {
+            serde_json::Value::String(s) => s,
+            _ => panic!(),
+        };
+        assert_eq!(parts[0].expected, show(&expected.non_snark.ledger_hash));
+        assert_eq!(parts[0].actual, show(&actual.non_snark.ledger_hash));
+    }
+
+    #[test]
+    fn touched_by_payments() {
+        let key = |address: &str| CompressedPubKey::from_address(address).unwrap();
+        let a = key("B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg");
+        let b = CompressedPubKey {
+            x: a.x,
+            is_odd: !a.is_odd,
+        };
+        let c = CompressedPubKey {
+            x: a.x + Fp::from(1u64),
+            is_odd: a.is_odd,
+        };
+        let payment = |from: &CompressedPubKey, to: &CompressedPubKey| WithStatus {
+            data: UserCommand::SignedCommand(Box::new(SignedCommand {
+                payload: SignedCommandPayload {
+                    common: Common {
+                        fee: Fee::from_u64(1_000_000),
+                        fee_payer_pk: from.clone(),
+                        nonce: Nonce::from_u32(0),
+                        valid_until: Slot::from_u32(u32::MAX),
+                        memo: Memo::empty(),
+                    },
+                    body: Body::Payment(PaymentPayload {
+                        receiver_pk: to.clone(),
+                        amount: Amount::from_u64(1000),
+                    }),
+                },
+                signer: from.clone(),
+                signature: Signature::new(Fp::from(1u64), Fq::from(1u64)),
+            })),
+            status: TransactionStatus::Applied,
+        };
+        let mut diff = Diff::empty();
+        diff.diff.0.commands = vec![payment(&a, &b), payment(&b, &a), payment(&b, &c)];
+
+        let id =
+            |public_key: &CompressedPubKey| AccountId::new(public_key.clone(), TokenId::default());
+        // the coinbase receiver first, then in the order of the commands, once each
+        assert_eq!(touched_accounts(&diff, &c), vec![id(&c), id(&a), id(&b)]);
+        assert_eq!(touched_accounts(&Diff::empty(), &a), vec![id(&a)]);
+    }
+
+    #[test]
+    fn supercharge_by_timing() {
+        let mut winner = Account::empty();
+        winner.balance = Balance::of_nanomina_int_exn(10_000);
+        assert!(supercharge_coinbase(&winner, 0));
+
+        // 6000 locked until the cliff at 100, 1000 released at the cliff,
+        // then 1000 every 10 slots
+        winner.timing = Timing::Timed {
+            initial_minimum_balance: Balance::of_nanomina_int_exn(6000),
+            cliff_time: Slot::from_u32(100),
+            cliff_amount: Amount::from_u64(1000),
+            vesting_period: SlotSpan::from_u32(10),
+            vesting_increment: Amount::from_u64(1000),
+        };
+        let locked = [
+            (0, 6000),
+            (99, 6000),
+            (100, 5000),
+            (109, 5000),
+            (110, 4000),
+            (149, 1000),
+        ];
+        for (slot, min_balance) in locked {
+            assert_eq!(min_balance_at_slot(&winner.timing, slot), min_balance);
+            assert!(!supercharge_coinbase(&winner, slot));
+        }
+        for slot in [150, 151, 10_000, u32::MAX] {
+            assert_eq!(min_balance_at_slot(&winner.timing, slot), 0);
+            assert!(supercharge_coinbase(&winner, slot));
+        }
+
+        // everything vests at the cliff
+        winner.timing = Timing::Timed {
+            initial_minimum_balance: Balance::of_nanomina_int_exn(6000),
+            cliff_time: Slot::from_u32(100),
+            cliff_amount: Amount::from_u64(6000),
+            vesting_period: SlotSpan::from_u32(0),
+            vesting_increment: Amount::from_u64(0),
+        };
+        assert!(!supercharge_coinbase(&winner, 99));
+        assert!(supercharge_coinbase(&winner, 100));
+    }
+
+    /// The lookup of the winner in the staking ledger and its errors. The expected flags
+    /// follow the timing rule of `supercharge_by_timing`, they are not taken from
+    /// recorded blocks.
+    #[test]
+    fn supercharge_from_staking_ledger() {
+        let fixture =
+            serde_json::from_str::<serde_json::Value>(include_str!("supercharge_blocks.json"))
+                .unwrap();
+        let accounts = runtime_config::import(&fixture).unwrap();
+        let mut staking_ledger = SnarkedLedger::empty();
+        for (i, account) in accounts.iter().enumerate() {
+            staking_ledger
+                .inner
+                .set_at_index(AccountIndex(i as u64), Box::new(account.clone()))
+                .unwrap();
+        }
+        staking_ledger.num = accounts.len() as u32;
+        let hash = staking_ledger.hash_at(0, 0).unwrap().to_string();
+        let mut ledgers = BTreeMap::<_, Box<dyn LedgerView>>::new();
+        ledgers.insert(hash.clone(), Box::new(staking_ledger));
+
+        let blocks = fixture["blocks"].as_array().unwrap();
+        let mut flags = vec![];
+        for block in blocks {
+            let winner = block["block_stake_winner"].as_str().unwrap();
+            let winner = CompressedPubKey::from_address(winner).unwrap();
+            let global_slot = block["global_slot_since_genesis"].as_u64().unwrap() as u32;
+            let expected = block["supercharge_coinbase"].as_bool().unwrap();
+            let actual = supercharge_of_block(&mut ledgers, &hash, &winner, global_slot).unwrap();
+            assert_eq!(actual, expected);
+            flags.push(actual);
+        }
+        assert_eq!(flags, [true, false]);
+
+        // no fallback, the winner must be in the staking ledger
+        let mut stranger = accounts[0].public_key.clone();
+        stranger.is_odd = !stranger.is_odd;
+        assert!(matches!(
+            supercharge_of_block(&mut ledgers, &hash, &stranger, 7142),
+            Err(ApplyError::UnknownWinner { .. })
+        ));
+        assert!(matches!(
+            supercharge_of_block(&mut ledgers, "unknown", &accounts[0].public_key, 7142),
+            Err(ApplyError::UnknownStakingLedger(_))
+        ));
     }
 }
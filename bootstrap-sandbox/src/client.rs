@@ -1,3 +1,9 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
 use binprot::BinProtRead;
 use libp2p::{Swarm, futures::StreamExt, swarm::SwarmEvent, PeerId};
 use mina_p2p_messages::{
@@ -5,14 +11,66 @@ use mina_p2p_messages::{
     rpc::GetBestTipV2,
 };
 use libp2p_rpc_behaviour::{Behaviour, Event, StreamId, Received};
+use mina_transport::BehaviourEvent;
 
 use thiserror::Error;
 
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_ATTEMPTS: usize = 3;
+/// Requests sent to one peer at the same time.
+const MAX_IN_FLIGHT: usize = 4;
+
+/// Answers the queries of the peers while the client waits for its own responses.
+pub trait Responder {
+    /// The payload of the query is in `bytes`, the header is already read.
+    fn respond(
+        &mut self,
+        rpc: &mut Behaviour,
+        peer_id: PeerId,
+        stream_id: StreamId,
+        header: QueryHeader,
+        bytes: &[u8],
+    ) -> Result<(), binprot::Error>;
+}
+
+/// Has no best tip, and answers every other query with the error `Unimplemented_rpc`.
+pub struct DefaultResponder;
+
+impl Responder for DefaultResponder {
+    fn respond(
+        &mut self,
+        rpc: &mut Behaviour,
+        peer_id: PeerId,
+        stream_id: StreamId,
+        header: QueryHeader,
+        _bytes: &[u8],
+    ) -> Result<(), binprot::Error> {
+        let QueryHeader { tag, version, id } = header;
+        let tag = tag.to_string_lossy();
+        if tag == GetBestTipV2::NAME && version == GetBestTipV2::VERSION {
+            rpc.respond::<GetBestTipV2>(peer_id, stream_id, id, Ok(None))
+        } else {
+            log::debug!("unimplemented query: {tag} {version}");
+            rpc.respond_unimplemented(peer_id, stream_id, id, &tag, version);
+            Ok(())
+        }
+    }
+}
+
+/// Keeps a pool of the connected peers that speak RPC, spreads the requests across them
+/// and sends a failed request again to another peer.
 pub struct Client {
-    swarm: Swarm<Behaviour>,
-    peer: Option<PeerId>,
-    stream: Option<StreamId>,
+    swarm: Swarm<mina_transport::Behaviour<Behaviour>>,
+    peers: BTreeMap<PeerId, Peer>,
+    requests: BTreeMap<u64, Request>,
+    /// The peer and the id of the query on the wire, mapped to the request.
+    in_flight: BTreeMap<(PeerId, i64), u64>,
+    /// The response and the peer that sent it.
+    done: BTreeMap<u64, Result<(PeerId, Box<dyn Any>), ClientError>>,
+    next_request: u64,
     id: i64,
+    timeout: Duration,
+    responder: Box<dyn Responder>,
 }
 
 #[derive(Debug, Error)]
@@ -23,103 +81,583 @@ pub enum ClientError {
     InternalError(rpc_kernel::Error),
     #[error("libp2p stop working")]
     Libp2p,
+    #[error("timeout")]
+    Timeout,
+    #[error("connection closed")]
+    ConnectionClosed,
+    #[error("no peer can answer {0}")]
+    NoPeer(&'static str),
+}
+
+/// What the client knows about the quality of the peer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Score {
+    /// Moving average.
+    pub latency: Option<Duration>,
+    pub successes: u32,
+    pub failures: u32,
+}
+
+impl Score {
+    fn success(&mut self, latency: Duration) {
+        self.successes += 1;
+        self.latency = Some(match self.latency {
+            Some(average) => (average * 4 + latency) / 5,
+            None => latency,
+        });
+    }
+
+    fn failure(&mut self) {
+        self.failures += 1;
+    }
+
+    /// The expected latency divided by the success rate, the lower the better.
+    /// Unknown peers are expected to answer in a second.
+    pub fn cost(&self) -> f64 {
+        let latency = self.latency.map_or(1.0, |latency| latency.as_secs_f64());
+        let rate = (self.successes as f64 + 1.0) / ((self.successes + self.failures) as f64 + 2.0);
+        latency / rate
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    stream: Option<StreamId>,
+    /// The methods the peer serves, if it told us.
+    menu: Option<BTreeSet<(String, i32)>>,
+    in_flight: usize,
+    score: Score,
+    /// Sent a wrong response, the client does not ask it anymore.
+    blamed: bool,
+}
+
+type SendQuery = Box<dyn Fn(&mut Behaviour, PeerId, StreamId, i64) -> Result<(), binprot::Error>>;
+type Decode = fn(&[u8]) -> Result<Box<dyn Any>, ClientError>;
+
+struct Request {
+    tag: &'static str,
+    version: i32,
+    send: SendQuery,
+    decode: Decode,
+    attempts: usize,
+    tried: BTreeSet<PeerId>,
+    /// The client never sends the request to these peers.
+    excluded: BTreeSet<PeerId>,
+    sent: Option<(PeerId, i64, Instant)>,
+}
+
+fn decode<M>(mut bytes: &[u8]) -> Result<Box<dyn Any>, ClientError>
+where
+    M: RpcMethod,
+    M::Response: 'static,
+{
+    let response = ResponsePayload::<M::Response>::binprot_read(&mut bytes)?
+        .0
+        .map_err(ClientError::InternalError)?
+        .0;
+    Ok(Box::new(response))
 }
 
 impl Client {
-    pub fn new(swarm: Swarm<Behaviour>) -> Self {
+    pub fn new(swarm: Swarm<mina_transport::Behaviour<Behaviour>>) -> Self {
         Client {
             swarm,
-            peer: None,
-            stream: None,
+            peers: BTreeMap::default(),
+            requests: BTreeMap::default(),
+            in_flight: BTreeMap::default(),
+            done: BTreeMap::default(),
+            next_request: 0,
             id: 1,
+            timeout: DEFAULT_TIMEOUT,
+            responder: Box::new(DefaultResponder),
         }
     }
 
+    /// How long to wait for a response before asking another peer.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answer the queries of the peers with this responder instead of [`DefaultResponder`].
+    pub fn with_responder<R>(mut self, responder: R) -> Self
+    where
+        R: Responder + 'static,
+    {
+        self.responder = Box::new(responder);
+        self
+    }
+
+    pub fn scores(&self) -> impl Iterator<Item = (&PeerId, &Score)> {
+        self.peers
+            .iter()
+            .map(|(peer_id, peer)| (peer_id, &peer.score))
+    }
+
+    /// The response of the peer is well-formed but wrong, do not send it requests anymore.
+    pub fn blame(&mut self, peer_id: PeerId) {
+        log::warn!("peer {peer_id} sent a wrong response, blamed");
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.score.failure();
+            peer.blamed = true;
+        }
+    }
+
+    pub fn blamed(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.blamed)
+            .map(|(peer_id, _)| peer_id)
+    }
+
     pub async fn rpc<M>(&mut self, query: M::Query) -> Result<M::Response, ClientError>
+    where
+        M: RpcMethod + 'static,
+        M::Query: Clone + 'static,
+        M::Response: 'static,
+    {
+        let request = self.start::<M>(query);
+        self.wait::<M>(request).await
+    }
+
+    /// Send all the queries at once, the results are in the same order.
+    pub async fn rpc_many<M, I>(&mut self, queries: I) -> Vec<Result<M::Response, ClientError>>
+    where
+        M: RpcMethod + 'static,
+        M::Query: Clone + 'static,
+        M::Response: 'static,
+        I: IntoIterator<Item = M::Query>,
+    {
+        let requests = queries
+            .into_iter()
+            .map(|query| self.start::<M>(query))
+            .collect::<Vec<_>>();
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            results.push(self.wait::<M>(request).await);
+        }
+        results
+    }
+
+    /// Send the query without waiting, take the response with [`Client::wait_any`].
+    pub fn start<M>(&mut self, query: M::Query) -> u64
+    where
+        M: RpcMethod + 'static,
+        M::Query: Clone + 'static,
+        M::Response: 'static,
+    {
+        self.start_excluding::<M>(query, BTreeSet::default())
+    }
+
+    /// Like [`Client::start`], but never send the query to the `excluded` peers,
+    /// for example to the peers that could not answer it before.
+    pub fn start_excluding<M>(&mut self, query: M::Query, excluded: BTreeSet<PeerId>) -> u64
+    where
+        M: RpcMethod + 'static,
+        M::Query: Clone + 'static,
+        M::Response: 'static,
+    {
+        let request = self.next_request;
+        self.next_request += 1;
+        self.requests.insert(
+            request,
+            Request {
+                tag: M::NAME,
+                version: M::VERSION,
+                send: Box::new(move |behaviour, peer_id, stream_id, id| {
+                    behaviour.query::<M>(peer_id, stream_id, id, query.clone())
+                }),
+                decode: decode::<M>,
+                attempts: 0,
+                tried: BTreeSet::default(),
+                excluded,
+                sent: None,
+            },
+        );
+        request
+    }
+
+    /// Wait for any of the requests, they must be of the same method and not taken yet.
+    /// The response comes with the peer that sent it.
+    pub async fn wait_any<M>(
+        &mut self,
+        requests: &BTreeSet<u64>,
+    ) -> (u64, Result<(PeerId, M::Response), ClientError>)
     where
         M: RpcMethod,
+        M::Response: 'static,
     {
-        let mut query = Some(query);
-        if let (Some(peer_id), Some(stream_id)) = (self.peer, self.stream) {
-            if let Some(query) = query.take() {
-                self.swarm
-                    .behaviour_mut()
-                    .query::<M>(peer_id, stream_id, self.id, query)?;
-                self.id += 1;
+        loop {
+            // dispatch first, it fails the requests that no peer can answer
+            self.dispatch();
+            if let Some(request) = requests.iter().find(|r| self.done.contains_key(r)) {
+                let request = *request;
+                return (request, self.take::<M>(request).expect("just found"));
+            }
+            if let Err(err) = self.step().await {
+                let request = *requests.iter().next().expect("must not be empty");
+                self.requests.remove(&request);
+                return (request, Err(err));
             }
         }
+    }
 
+    async fn wait<M>(&mut self, request: u64) -> Result<M::Response, ClientError>
+    where
+        M: RpcMethod,
+        M::Response: 'static,
+    {
         loop {
-            match self.swarm.next().await.ok_or(ClientError::Libp2p)? {
-                SwarmEvent::Behaviour((peer_id, Event::ConnectionEstablished)) => {
-                    log::info!("new connection {peer_id}");
+            self.dispatch();
+            if let Some(result) = self.take::<M>(request) {
+                return result.map(|(_, response)| response);
+            }
+            self.step().await?;
+        }
+    }
 
-                    self.peer = Some(peer_id);
-                    self.swarm.behaviour_mut().open(peer_id, 0);
+    fn take<M>(&mut self, request: u64) -> Option<Result<(PeerId, M::Response), ClientError>>
+    where
+        M: RpcMethod,
+        M::Response: 'static,
+    {
+        let result = self.done.remove(&request)?;
+        Some(result.map(|(peer_id, response)| {
+            let response = response
+                .downcast::<M::Response>()
+                .expect("the request is decoded as its own method");
+            (peer_id, *response)
+        }))
+    }
+
+    /// The best peer that can take one more request of this method,
+    /// a peer that already failed the request only if there is no other.
+    /// `Err` if the request waits for no reason: every peer is blamed,
+    /// excluded or does not serve the method, and none is still connecting.
+    fn pick_peer(
+        peers: &BTreeMap<PeerId, Peer>,
+        request: &Request,
+    ) -> Result<Option<PeerId>, ClientError> {
+        let method = (request.tag.to_owned(), request.version);
+        let usable = |(peer_id, peer): &(&PeerId, &Peer)| {
+            !peer.blamed && !request.excluded.contains(*peer_id)
+        };
+        let mut eligible = peers
+            .iter()
+            .filter(|(_, peer)| peer.stream.is_some())
+            .filter(usable)
+            .filter(|(_, peer)| {
+                peer.menu
+                    .as_ref()
+                    .map_or(true, |menu| menu.contains(&method))
+            })
+            .peekable();
+        if eligible.peek().is_none() {
+            // no peer is known yet, or one that could serve it is still connecting
+            let connecting = peers.is_empty()
+                || peers
+                    .iter()
+                    .filter(|(_, peer)| peer.stream.is_none())
+                    .any(|entry| usable(&entry));
+            return if connecting {
+                Ok(None)
+            } else {
+                Err(ClientError::NoPeer(request.tag))
+            };
+        }
+        Ok(eligible
+            .filter(|(_, peer)| peer.in_flight < MAX_IN_FLIGHT)
+            .min_by(|(a_id, a), (b_id, b)| {
+                let a_tried = request.tried.contains(*a_id);
+                let b_tried = request.tried.contains(*b_id);
+                a_tried
+                    .cmp(&b_tried)
+                    .then(a.score.cost().total_cmp(&b.score.cost()))
+                    .then(a.in_flight.cmp(&b.in_flight))
+            })
+            .map(|(peer_id, _)| *peer_id))
+    }
+
+    fn dispatch(&mut self) {
+        let waiting = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.sent.is_none())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for request_id in waiting {
+            let peer_id = match Self::pick_peer(&self.peers, &self.requests[&request_id]) {
+                Ok(Some(peer_id)) => peer_id,
+                Ok(None) => continue,
+                Err(err) => {
+                    self.requests.remove(&request_id);
+                    self.done.insert(request_id, Err(err));
+                    continue;
                 }
-                SwarmEvent::Behaviour((peer_id, Event::ConnectionClosed)) => {
-                    log::info!("connection closed {peer_id}");
-                    if self.peer == Some(peer_id) {
-                        self.peer = None;
-                        // TODO: resend
-                    }
+            };
+            let peer = self.peers.get_mut(&peer_id).expect("just picked");
+            let stream_id = peer.stream.expect("picked only with stream");
+            let request = self.requests.get_mut(&request_id).expect("must exist");
+            let id = self.id;
+            self.id += 1;
+            match (request.send)(
+                &mut self.swarm.behaviour_mut().inner,
+                peer_id,
+                stream_id,
+                id,
+            ) {
+                Ok(()) => {
+                    log::debug!("{} to {peer_id}, id {id}", request.tag);
+                    peer.in_flight += 1;
+                    request.attempts += 1;
+                    request.tried.insert(peer_id);
+                    request.sent = Some((peer_id, id, Instant::now()));
+                    self.in_flight.insert((peer_id, id), request_id);
                 }
-                SwarmEvent::Behaviour((
-                    peer_id,
-                    Event::Stream {
-                        stream_id,
-                        received,
-                    },
-                )) => match received {
-                    Received::HandshakeDone => {
-                        log::info!("new stream {peer_id} {stream_id:?}");
-                        if self.stream.is_none() {
-                            self.stream = Some(stream_id);
-                        }
-
-                        if let (Some(peer_id), Some(stream_id)) = (self.peer, self.stream) {
-                            if let Some(query) = query.take() {
-                                self.swarm
-                                    .behaviour_mut()
-                                    .query::<M>(peer_id, stream_id, self.id, query)?;
-                                self.id += 1;
-                            }
-                        }
-                    }
-                    Received::Menu(menu) => {
-                        log::info!("menu: {menu:?}");
+                Err(err) => {
+                    self.requests.remove(&request_id);
+                    self.done.insert(request_id, Err(err.into()));
+                }
+            }
+        }
+    }
+
+    /// The request is done if there are no attempts left, otherwise it waits for another peer.
+    fn fail(&mut self, request_id: u64, err: ClientError) {
+        let Some(request) = self.requests.get_mut(&request_id) else {
+            return;
+        };
+        if let Some((peer_id, id, _)) = request.sent.take() {
+            self.in_flight.remove(&(peer_id, id));
+            if let Some(peer) = self.peers.get_mut(&peer_id) {
+                peer.in_flight -= 1;
+                peer.score.failure();
+            }
+            log::warn!("{} failed at {peer_id}, {err}", request.tag);
+        }
+        if request.attempts >= MAX_ATTEMPTS {
+            self.requests.remove(&request_id);
+            self.done.insert(request_id, Err(err));
+        }
+    }
+
+    fn on_response(&mut self, peer_id: PeerId, id: i64, bytes: &[u8]) {
+        let Some(request_id) = self.in_flight.remove(&(peer_id, id)) else {
+            // the request timed out and went to another peer
+            return;
+        };
+        let request = self.requests.get(&request_id).expect("must exist");
+        match (request.decode)(bytes) {
+            Ok(response) => {
+                let (_, _, time) = request.sent.expect("the request is in flight");
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.in_flight -= 1;
+                    peer.score.success(time.elapsed());
+                }
+                self.requests.remove(&request_id);
+                self.done.insert(request_id, Ok((peer_id, response)));
+            }
+            Err(err) => {
+                // put it back so `fail` updates the peer
+                self.in_flight.insert((peer_id, id), request_id);
+                self.fail(request_id, err);
+            }
+        }
+    }
+
+    fn on_disconnected(&mut self, peer_id: PeerId) {
+        self.peers.remove(&peer_id);
+        let lost = self
+            .in_flight
+            .range((peer_id, i64::MIN)..=(peer_id, i64::MAX))
+            .map(|(_, request_id)| *request_id)
+            .collect::<Vec<_>>();
+        for request_id in lost {
+            self.fail(request_id, ClientError::ConnectionClosed);
+        }
+    }
+
+    fn check_timeouts(&mut self) {
+        let timeout = self.timeout;
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, request)| {
+                request
+                    .sent
+                    .is_some_and(|(_, _, time)| time.elapsed() >= timeout)
+            })
+            .map(|(request_id, _)| *request_id)
+            .collect::<Vec<_>>();
+        for request_id in expired {
+            self.fail(request_id, ClientError::Timeout);
+        }
+    }
+
+    /// Handle one event of the swarm, or the timeout of the oldest request in flight.
+    async fn step(&mut self) -> Result<(), ClientError> {
+        let deadline = self
+            .requests
+            .values()
+            .filter_map(|request| request.sent.map(|(_, _, time)| time + self.timeout))
+            .min();
+        let event = match deadline {
+            Some(deadline) => {
+                let deadline = tokio::time::Instant::from_std(deadline);
+                match tokio::time::timeout_at(deadline, self.swarm.next()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        self.check_timeouts();
+                        return Ok(());
                     }
-                    Received::Query {
-                        header: QueryHeader { tag, version, id },
-                        bytes,
-                    } => {
-                        if tag.to_string_lossy() == "get_best_tip" && version == 2 {
-                            let _ = bytes;
-                            self.swarm
-                                .behaviour_mut()
-                                .respond::<GetBestTipV2>(peer_id, stream_id, id, Ok(None))
-                                .unwrap();
-                        } else {
-                            log::warn!("unhandled query: {tag} {version}");
-                        }
+                }
+            }
+            None => self.swarm.next().await,
+        };
+
+        match event.ok_or(ClientError::Libp2p)? {
+            SwarmEvent::Behaviour(BehaviourEvent::Inner((
+                peer_id,
+                Event::ConnectionEstablished,
+            ))) => {
+                if !self.peers.contains_key(&peer_id) {
+                    log::info!("new connection {peer_id}");
+                    self.peers.insert(peer_id, Peer::default());
+                    self.swarm.behaviour_mut().inner.open(peer_id, 0);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Inner((peer_id, Event::ConnectionClosed))) => {
+                if !self.swarm.is_connected(&peer_id) {
+                    log::info!("connection closed {peer_id}");
+                    self.on_disconnected(peer_id);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Inner((
+                peer_id,
+                Event::Stream {
+                    stream_id,
+                    received,
+                },
+            ))) => match received {
+                Received::HandshakeDone => {
+                    log::info!("new stream {peer_id} {stream_id:?}");
+                    let peer = self.peers.entry(peer_id).or_default();
+                    if peer.stream.is_none() {
+                        peer.stream = Some(stream_id);
                     }
-                    Received::Response {
-                        header: ResponseHeader { id },
-                        bytes,
-                    } => {
-                        if id + 1 == self.id {
-                            let mut bytes = bytes.as_slice();
-                            let response =
-                                ResponsePayload::<M::Response>::binprot_read(&mut bytes)?
-                                    .0
-                                    .map_err(ClientError::InternalError)?
-                                    .0;
-                            return Ok(response);
-                        }
+                }
+                Received::Menu(menu) => {
+                    log::info!("menu: {menu:?}");
+                    self.peers.entry(peer_id).or_default().menu = Some(menu.into_iter().collect());
+                }
+                Received::Query { header, bytes } => {
+                    let rpc = &mut self.swarm.behaviour_mut().inner;
+                    let tag = header.tag.to_string_lossy().to_string();
+                    let (id, version) = (header.id, header.version);
+                    if let Err(err) = self
+                        .responder
+                        .respond(rpc, peer_id, stream_id, header, &bytes)
+                    {
+                        log::warn!("cannot answer {tag} {version}, {err}");
+                        rpc.respond_unimplemented(peer_id, stream_id, id, &tag, version);
                     }
-                },
-                _ => {}
-            }
+                }
+                Received::Response {
+                    header: ResponseHeader { id },
+                    bytes,
+                } => self.on_response(peer_id, id, &bytes),
+            },
+            _ => {}
+        }
+        self.check_timeouts();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(excluded: &[PeerId]) -> Request {
+        Request {
+            tag: GetBestTipV2::NAME,
+            version: GetBestTipV2::VERSION,
+            send: Box::new(|_, _, _, _| Ok(())),
+            decode: decode::<GetBestTipV2>,
+            attempts: 0,
+            tried: BTreeSet::default(),
+            excluded: excluded.iter().copied().collect(),
+            sent: None,
+        }
+    }
+
+    fn ready() -> Peer {
+        Peer {
+            stream: Some(StreamId::Incoming(0)),
+            ..Peer::default()
         }
     }
+
+    #[test]
+    fn pick_peer() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut peers = BTreeMap::new();
+        // no peer is ready yet, wait
+        assert!(matches!(Client::pick_peer(&peers, &request(&[])), Ok(None)));
+
+        peers.insert(a, ready());
+        peers.insert(b, ready());
+        let mut tried = request(&[]);
+        tried.tried.insert(a);
+        assert!(matches!(Client::pick_peer(&peers, &tried), Ok(Some(p)) if p == b));
+        assert!(matches!(Client::pick_peer(&peers, &request(&[b])), Ok(Some(p)) if p == a));
+
+        // busy, wait
+        peers.get_mut(&a).unwrap().in_flight = MAX_IN_FLIGHT;
+        assert!(matches!(
+            Client::pick_peer(&peers, &request(&[b])),
+            Ok(None)
+        ));
+
+        peers.get_mut(&a).unwrap().blamed = true;
+        assert!(matches!(
+            Client::pick_peer(&peers, &request(&[b])),
+            Err(ClientError::NoPeer(_))
+        ));
+
+        // does not serve the method
+        peers.get_mut(&b).unwrap().menu = Some(BTreeSet::default());
+        assert!(matches!(
+            Client::pick_peer(&peers, &request(&[])),
+            Err(ClientError::NoPeer(_))
+        ));
+
+        // another peer is still connecting, wait for it
+        let c = PeerId::random();
+        peers.insert(c, Peer::default());
+        assert!(matches!(Client::pick_peer(&peers, &request(&[])), Ok(None)));
+        assert!(matches!(
+            Client::pick_peer(&peers, &request(&[c])),
+            Err(ClientError::NoPeer(_))
+        ));
+    }
+
+    #[test]
+    fn score() {
+        let unknown = Score::default();
+
+        let mut fast = Score::default();
+        fast.success(Duration::from_millis(100));
+        assert!(fast.cost() < unknown.cost());
+
+        let mut failing = fast;
+        failing.failure();
+        failing.failure();
+        assert!(fast.cost() < failing.cost());
+
+        let mut slow = Score::default();
+        slow.success(Duration::from_secs(5));
+        assert!(unknown.cost() < slow.cost());
+    }
 }
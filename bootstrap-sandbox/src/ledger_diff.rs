@@ -0,0 +1,166 @@
+//! What changed between two ledgers, for example the snarked ledgers at two heights,
+//! or the staking and the next epoch ledgers.
+//!
+//! Both merkle trees are walked from the root, only the subtrees whose hashes differ
+//! are visited, so the cost is proportional to the number of changed accounts.
+
+use serde::Serialize;
+
+use super::{
+    runtime_config,
+    snarked_ledger::{Error, LedgerView},
+};
+
+const DEPTH: u32 = 35;
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub index: u64,
+    pub account: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct Modified {
+    pub index: u64,
+    pub pk: serde_json::Value,
+    /// Only the fields that differ, in the format of [`runtime_config::export_account`].
+    pub changes: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Default, Serialize)]
+pub struct LedgerDiff {
+    pub added: Vec<Entry>,
+    pub removed: Vec<Entry>,
+    pub modified: Vec<Modified>,
+}
+
+fn changes(
+    before: &serde_json::Value,
+    after: &serde_json::Value,
+) -> serde_json::Map<String, serde_json::Value> {
+    let null = serde_json::Value::Null;
+    let (Some(before), Some(after)) = (before.as_object(), after.as_object()) else {
+        return Default::default();
+    };
+    let mut keys = before.keys().collect::<Vec<_>>();
+    keys.extend(after.keys().filter(|key| !before.contains_key(*key)));
+    keys.into_iter()
+        .filter_map(|key| {
+            let (b, a) = (before.get(key), after.get(key));
+            (b != a).then(|| {
+                let change = Change {
+                    before: b.unwrap_or(&null).clone(),
+                    after: a.unwrap_or(&null).clone(),
+                };
+                let change = serde_json::to_value(change).expect("plain struct");
+                (key.clone(), change)
+            })
+        })
+        .collect()
+}
+
+/// Descends only into the subtrees whose hashes differ, the result is ordered by index.
+pub fn diff<A, B>(before: &mut A, after: &mut B) -> Result<LedgerDiff, Error>
+where
+    A: LedgerView + ?Sized,
+    B: LedgerView + ?Sized,
+{
+    let mut diff = LedgerDiff::default();
+    let mut stack = vec![(0, 0)];
+    while let Some((depth, pos)) = stack.pop() {
+        if before.hash_at(depth, pos)? == after.hash_at(depth, pos)? {
+            continue;
+        }
+        if depth < DEPTH {
+            // the left one is on top, visited first
+            stack.push((depth + 1, pos * 2 + 1));
+            stack.push((depth + 1, pos * 2));
+            continue;
+        }
+        let index = pos;
+        match (before.account_at(index)?, after.account_at(index)?) {
+            (None, None) => {}
+            (None, Some(account)) => diff.added.push(Entry {
+                index,
+                account: runtime_config::export_account(&account),
+            }),
+            (Some(account), None) => diff.removed.push(Entry {
+                index,
+                account: runtime_config::export_account(&account),
+            }),
+            (Some(b), Some(a)) => {
+                let (b, a) = (
+                    runtime_config::export_account(&b),
+                    runtime_config::export_account(&a),
+                );
+                diff.modified.push(Modified {
+                    index,
+                    pk: a.get("pk").cloned().unwrap_or_default(),
+                    changes: changes(&b, &a),
+                });
+            }
+        }
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_signer::CompressedPubKey;
+    use mina_tree::{scan_state::currency::Balance, Account, AccountIndex};
+
+    use super::{super::snarked_ledger::SnarkedLedger, *};
+
+    fn ledger(balances: &[u64]) -> SnarkedLedger {
+        let public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+        let mut ledger = SnarkedLedger::empty();
+        for (i, balance) in balances.iter().enumerate() {
+            let mut account = Account::empty();
+            account.public_key = public_key.clone();
+            account.balance = Balance::of_nanomina_int_exn(*balance);
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i as u64), Box::new(account))
+                .unwrap();
+        }
+        ledger.num = balances.len() as u32;
+        ledger
+    }
+
+    #[test]
+    fn added_and_modified() {
+        let mut before = ledger(&[1000, 2000, 3000, 4000]);
+        let mut after = ledger(&[1000, 2500, 3000, 4000, 5000]);
+
+        let forward = diff(&mut before, &mut after).unwrap();
+        assert!(forward.removed.is_empty());
+        assert_eq!(
+            forward.added.iter().map(|e| e.index).collect::<Vec<_>>(),
+            [4]
+        );
+        assert_eq!(forward.modified.len(), 1);
+        assert_eq!(forward.modified[0].index, 1);
+        let changed = forward.modified[0].changes.keys().collect::<Vec<_>>();
+        assert_eq!(changed, ["balance"]);
+        assert_eq!(
+            forward.modified[0].changes["balance"]["after"],
+            "0.000002500"
+        );
+
+        let backward = diff(&mut after, &mut before).unwrap();
+        assert_eq!(backward.removed[0].index, 4);
+
+        let mut same = ledger(&[1000, 2000, 3000, 4000]);
+        let none = diff(&mut before, &mut same).unwrap();
+        assert!(none.added.is_empty() && none.removed.is_empty() && none.modified.is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+//! Ledger files named by their merkle root, like the recorded `ledgers/` of a height,
+//! or the fixtures in `test_ledgers/`, in either format that [`snapshot::load`] reads.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use thiserror::Error;
+
+use super::{
+    snapshot,
+    snarked_ledger::{self, LedgerView, SnarkedLedger},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Snapshot(#[from] snapshot::Error),
+    #[error("{0}")]
+    Ledger(#[from] snarked_ledger::Error),
+    #[error("the file {name} has the merkle root {actual}")]
+    RootMismatch { name: String, actual: String },
+}
+
+/// Load the ledger and check that the file is named by its merkle root.
+pub fn load_checked<P>(path: P) -> Result<SnarkedLedger, Error>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut ledger = snapshot::load(path)?;
+    let actual = ledger.hash_at(0, 0)?.to_string();
+    if actual != name {
+        return Err(Error::RootMismatch { name, actual });
+    }
+    Ok(ledger)
+}
+
+/// All the ledgers in the directory by name, each checked by [`load_checked`].
+pub fn load_dir<P>(dir: P) -> Result<BTreeMap<String, SnarkedLedger>, Error>
+where
+    P: AsRef<Path>,
+{
+    let mut ledgers = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        ledgers.insert(name, load_checked(entry.path())?);
+    }
+    Ok(ledgers)
+}
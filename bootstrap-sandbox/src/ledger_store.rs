@@ -0,0 +1,489 @@
+//! Ledgers in an embedded database on disk, for the ledgers that don't fit in memory.
+//!
+//! Each ledger is a tree of the database named by the ledger hash. The accounts and
+//! the inner hashes are read when asked, so a stored ledger serves the ledger sync
+//! queries and is the base of [`SnarkedLedger::sync_diff`] without being loaded.
+//! [`LedgerStore::sync_diff`] writes the synced accounts and hashes straight into
+//! the database. The ledgers are shared by all the heights of a record.
+
+use std::{path::Path, sync::Arc};
+
+use ark_ff::{BigInteger, PrimeField};
+
+use binprot::{BinProtRead, BinProtWrite};
+use libp2p::PeerId;
+use libp2p_rpc_behaviour::{Behaviour, StreamId};
+use mina_p2p_messages::{
+    core::Info,
+    rpc::AnswerSyncLedgerQueryV2,
+    rpc_kernel::{QueryHeader, QueryPayload, RpcMethod, RpcResult},
+    v2,
+};
+use mina_tree::{Account, AccountId, AccountIndex, TreeVersion, V2};
+use thiserror::Error;
+
+use super::{
+    client::{DefaultResponder, Responder},
+    snarked_ledger::{self, LedgerTarget, LedgerView, QuerySource, SnarkedLedger, SyncStats},
+    snapshot,
+};
+
+/// Written last, a ledger without it is incomplete and is not visible.
+const META: &[u8] = b"meta";
+const DEPTH: u32 = 35;
+const HASH: u8 = b'h';
+const ACCOUNT: u8 = b'a';
+/// The index of the account by its id.
+const ID: u8 = b'i';
+const BATCH: usize = 0x4000;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Sled(#[from] sled::Error),
+    #[error("{0}")]
+    Binprot(#[from] binprot::Error),
+    #[error("{0}")]
+    Snapshot(#[from] snapshot::Error),
+    #[error("ledger: {0}")]
+    Ledger(String),
+    #[error("sync: {0}")]
+    Sync(#[from] snarked_ledger::Error),
+}
+
+#[derive(Clone)]
+pub struct LedgerStore {
+    db: sled::Db,
+    empty: Arc<Vec<v2::LedgerHash>>,
+}
+
+fn hash_key(depth: u32, pos: u64) -> [u8; 10] {
+    let mut key = [HASH; 10];
+    key[1] = depth as u8;
+    key[2..].copy_from_slice(&pos.to_be_bytes());
+    key
+}
+
+fn account_key(index: u64) -> [u8; 9] {
+    let mut key = [ACCOUNT; 9];
+    key[1..].copy_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn id_key(id: &AccountId) -> Vec<u8> {
+    let mut key = vec![ID];
+    key.extend_from_slice(id.public_key.into_address().as_bytes());
+    key.extend_from_slice(&id.token_id.0.into_repr().to_bytes_le());
+    key
+}
+
+fn hash_name(hash: &v2::LedgerHash) -> Result<String, Error> {
+    match serde_json::to_value(hash) {
+        Ok(serde_json::Value::String(s)) => Ok(s),
+        _ => Err(Error::Ledger(format!("cannot name the ledger {hash:?}"))),
+    }
+}
+
+impl LedgerStore {
+    pub fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(LedgerStore {
+            db: sled::open(path)?,
+            empty: Arc::new(snapshot::empty_hashes()?),
+        })
+    }
+
+    /// The complete ledger with this hash, if it is stored.
+    pub fn ledger(&self, hash: &str) -> Result<Option<DiskLedger>, Error> {
+        let tree = self.db.open_tree(hash)?;
+        let Some(meta) = tree.get(META)? else {
+            return Ok(None);
+        };
+        let mut meta = meta.as_ref();
+        let num = u32::binprot_read(&mut meta)?;
+        let top_hash = Option::binprot_read(&mut meta)?;
+        Ok(Some(DiskLedger {
+            tree,
+            num,
+            top_hash,
+            empty: self.empty.clone(),
+        }))
+    }
+
+    /// The hashes of the complete ledgers.
+    pub fn hashes(&self) -> Result<Vec<String>, Error> {
+        let mut hashes = vec![];
+        for name in self.db.tree_names() {
+            if self.db.open_tree(&name)?.contains_key(META)? {
+                hashes.push(String::from_utf8_lossy(&name).into_owned());
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Write the ledger under this hash, replacing the stored one.
+    pub fn insert(&self, hash: &str, ledger: &mut SnarkedLedger) -> Result<DiskLedger, Error> {
+        self.db.drop_tree(hash)?;
+        let tree = self.db.open_tree(hash)?;
+
+        let into_store = |err: snarked_ledger::Error| Error::Ledger(err.to_string());
+        let len = ledger.inner.num_accounts() as u64;
+        let mut batch = sled::Batch::default();
+        let mut size = 0;
+        for depth in 0..=DEPTH {
+            // the subtrees at this depth that have accounts
+            let height = DEPTH - depth;
+            let count = (len + (1 << height) - 1) >> height;
+            for pos in 0..count {
+                let mut value = vec![];
+                let hash = ledger.hash_at(depth, pos).map_err(into_store)?;
+                hash.binprot_write(&mut value)?;
+                batch.insert(&hash_key(depth, pos)[..], value);
+                size += 1;
+                if size % BATCH == 0 {
+                    tree.apply_batch(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+        for index in 0..len {
+            if let Some(account) = ledger.account_at(index).map_err(into_store)? {
+                batch.insert(id_key(&account.id()), &index.to_be_bytes()[..]);
+                let mut value = vec![];
+                account.binprot_write(&mut value)?;
+                batch.insert(&account_key(index)[..], value);
+                size += 2;
+                if size % BATCH == 0 {
+                    tree.apply_batch(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+        tree.apply_batch(batch)?;
+
+        let mut meta = vec![];
+        ledger.num.binprot_write(&mut meta)?;
+        ledger.top_hash.binprot_write(&mut meta)?;
+        tree.insert(META, meta)?;
+        tree.flush()?;
+
+        Ok(DiskLedger {
+            tree,
+            num: ledger.num,
+            top_hash: ledger.top_hash.clone(),
+            empty: self.empty.clone(),
+        })
+    }
+
+    /// Sync the ledger with this root into the store, the accounts are not kept in memory.
+    /// Only the subtrees whose hashes differ from `base` are fetched, the rest is copied.
+    /// The sync that did not finish continues from the subtrees it has written.
+    pub async fn sync_diff<S, B>(
+        &self,
+        source: &mut S,
+        root: &v2::LedgerHash,
+        base: &mut B,
+    ) -> Result<(DiskLedger, SyncStats), Error>
+    where
+        S: QuerySource,
+        B: LedgerView + ?Sized,
+    {
+        let name = hash_name(root)?;
+        if let Some(ledger) = self.ledger(&name)? {
+            return Ok((ledger, SyncStats::default()));
+        }
+        let (num, top_hash) = snarked_ledger::num_accounts(source, root).await?;
+        let mut ledger = DiskLedger {
+            tree: self.db.open_tree(&name)?,
+            num,
+            top_hash: Some(top_hash),
+            empty: self.empty.clone(),
+        };
+        let stats = snarked_ledger::sync_into(source, root, &mut ledger, base).await?;
+
+        let mut meta = vec![];
+        ledger.num.binprot_write(&mut meta)?;
+        ledger.top_hash.binprot_write(&mut meta)?;
+        ledger.tree.insert(META, meta)?;
+        ledger.tree.flush()?;
+        Ok((ledger, stats))
+    }
+}
+
+/// Serves the ledger sync queries from the stored ledgers, the other queries like [`DefaultResponder`].
+impl Responder for LedgerStore {
+    fn respond(
+        &mut self,
+        rpc: &mut Behaviour,
+        peer_id: PeerId,
+        stream_id: StreamId,
+        header: QueryHeader,
+        mut bytes: &[u8],
+    ) -> Result<(), binprot::Error> {
+        type T = AnswerSyncLedgerQueryV2;
+        if header.tag.to_string_lossy() != T::NAME || header.version != T::VERSION {
+            return DefaultResponder.respond(rpc, peer_id, stream_id, header, bytes);
+        }
+        let (hash, query) = QueryPayload::<<T as RpcMethod>::Query>::binprot_read(&mut bytes)?.0;
+        let hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash));
+        let hash_str = match serde_json::to_value(&hash) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => String::new(),
+        };
+        let response = match self.ledger(&hash_str) {
+            Ok(Some(mut ledger)) => ledger.serve_query(query),
+            Ok(None) => {
+                let msg = format!("unknown ledger {hash_str}");
+                Err(Info::CouldNotConstruct(msg.as_str().into()))
+            }
+            Err(err) => Err(Info::CouldNotConstruct(err.to_string().as_str().into())),
+        };
+        rpc.respond::<T>(peer_id, stream_id, header.id, Ok(RpcResult(response)))
+    }
+}
+
+/// A stored ledger, reads the accounts and the hashes from the database.
+pub struct DiskLedger {
+    tree: sled::Tree,
+    num: u32,
+    top_hash: Option<v2::LedgerHash>,
+    empty: Arc<Vec<v2::LedgerHash>>,
+}
+
+impl DiskLedger {
+    /// Read all the accounts into a ledger in memory.
+    pub fn to_ledger(&self) -> Result<SnarkedLedger, Error> {
+        let mut ledger = SnarkedLedger::empty();
+        ledger.num = self.num;
+        ledger.top_hash = self.top_hash.clone();
+        for item in self.tree.scan_prefix([ACCOUNT]) {
+            let (key, value) = item?;
+            let index = u64::from_be_bytes(key[1..].try_into().expect("account key"));
+            let account = Account::binprot_read(&mut value.as_ref())?;
+            ledger
+                .inner
+                .set_at_index(AccountIndex(index), Box::new(account))
+                .map_err(|()| Error::Ledger(format!("cannot set the account {index}")))?;
+        }
+        Ok(ledger)
+    }
+}
+
+impl LedgerView for DiskLedger {
+    fn num_accounts(&self) -> u32 {
+        self.num
+    }
+
+    fn hash_at(&mut self, depth: u32, pos: u64) -> Result<v2::LedgerHash, snarked_ledger::Error> {
+        let into_ledger = |err: sled::Error| snarked_ledger::Error::Ledger(err.to_string());
+        match self.tree.get(hash_key(depth, pos)).map_err(into_ledger)? {
+            Some(value) => {
+                Ok(v2::LedgerHash::binprot_read(&mut value.as_ref())
+                    .map_err(snapshot::Error::from)?)
+            }
+            None => self
+                .empty
+                .get(depth as usize)
+                .cloned()
+                .ok_or_else(|| snarked_ledger::Error::Ledger(format!("depth {depth}"))),
+        }
+    }
+
+    fn account_at(&mut self, index: u64) -> Result<Option<Account>, snarked_ledger::Error> {
+        let into_ledger = |err: sled::Error| snarked_ledger::Error::Ledger(err.to_string());
+        match self.tree.get(account_key(index)).map_err(into_ledger)? {
+            Some(value) => Ok(Some(
+                Account::binprot_read(&mut value.as_ref()).map_err(snapshot::Error::from)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn index_of(&mut self, id: &AccountId) -> Result<Option<u64>, snarked_ledger::Error> {
+        let into_ledger = |err: sled::Error| snarked_ledger::Error::Ledger(err.to_string());
+        match self.tree.get(id_key(id)).map_err(into_ledger)? {
+            Some(value) => {
+                let bytes = value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| snarked_ledger::Error::Ledger(format!("wrong index of {id:?}")))?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl DiskLedger {
+    fn put<V>(&self, key: &[u8], value: V) -> Result<(), snarked_ledger::Error>
+    where
+        V: BinProtWrite,
+    {
+        let mut bytes = vec![];
+        value
+            .binprot_write(&mut bytes)
+            .map_err(snapshot::Error::from)?;
+        self.tree
+            .insert(key, bytes)
+            .map_err(|err| snarked_ledger::Error::Ledger(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Keeps every hash, so the hashes are updated as the subtrees are written.
+impl LedgerTarget for DiskLedger {
+    fn set_account(&mut self, index: u64, account: Account) -> Result<(), snarked_ledger::Error> {
+        let hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(account.hash().into()));
+        self.put(&hash_key(DEPTH, index), hash)?;
+        self.tree
+            .insert(id_key(&account.id()), &index.to_be_bytes()[..])
+            .map_err(|err| snarked_ledger::Error::Ledger(err.to_string()))?;
+        self.put(&account_key(index), account)
+    }
+
+    fn update_hash(&mut self, depth: u32, pos: u64) -> Result<(), snarked_ledger::Error> {
+        let left = self.hash_at(depth + 1, pos * 2)?.to_field();
+        let right = self.hash_at(depth + 1, pos * 2 + 1)?.to_field();
+        let hash = V2::hash_node((DEPTH - depth - 1) as usize, left, right);
+        let hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash.into()));
+        self.put(&hash_key(depth, pos), hash)
+    }
+
+    /// Copies the hashes too, the accounts first, the hash of the subtree last.
+    fn copy_subtree<B>(
+        &mut self,
+        base: &mut B,
+        depth: u32,
+        pos: u64,
+    ) -> Result<usize, snarked_ledger::Error>
+    where
+        B: LedgerView + ?Sized,
+    {
+        let height = DEPTH - depth;
+        let start = pos << height;
+        let end = (start + (1 << height)).min(base.num_accounts() as u64);
+        for index in start..end {
+            if let Some(account) = base.account_at(index)? {
+                self.set_account(index, account)?;
+            }
+        }
+        for inner in (depth..DEPTH).rev() {
+            let shift = DEPTH - inner;
+            let first = start >> shift;
+            let last = (end.max(start + 1) - 1) >> shift;
+            for pos in first..=last {
+                let hash = base.hash_at(inner, pos)?;
+                self.put(&hash_key(inner, pos), hash)?;
+            }
+        }
+        Ok(end.saturating_sub(start) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_signer::CompressedPubKey;
+    use mina_tree::scan_state::currency::Balance;
+
+    use super::*;
+
+    #[test]
+    fn store_and_serve() {
+        let dir = std::env::temp_dir().join(format!("ledger-store-{}", std::process::id()));
+
+        let public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+        let mut ledger = SnarkedLedger::empty();
+        for i in 0..21 {
+            let mut account = Account::empty();
+            account.public_key = public_key.clone();
+            account.balance = Balance::of_nanomina_int_exn(1000 + i);
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i), Box::new(account))
+                .unwrap();
+        }
+        ledger.num = 21;
+        let root = ledger.hash_at(0, 0).unwrap();
+        let name = match serde_json::to_value(&root).unwrap() {
+            serde_json::Value::String(s) => s,
+            _ => panic!(),
+        };
+
+        {
+            let store = LedgerStore::open(&dir).unwrap();
+            assert!(store.ledger(&name).unwrap().is_none());
+            store.insert(&name, &mut ledger).unwrap();
+        }
+
+        // reopened
+        let store = LedgerStore::open(&dir).unwrap();
+        assert_eq!(store.hashes().unwrap(), [name.clone()]);
+        let mut stored = store.ledger(&name).unwrap().unwrap();
+        assert_eq!(stored.hash_at(0, 0).unwrap(), root);
+        for (depth, pos) in [(32, 2), (32, 3), (1, 1), (35, 20)] {
+            assert_eq!(
+                stored.hash_at(depth, pos).unwrap(),
+                ledger.hash_at(depth, pos).unwrap()
+            );
+        }
+        for index in [0, 20, 21] {
+            assert_eq!(
+                stored.account_at(index).unwrap(),
+                ledger.account_at(index).unwrap()
+            );
+        }
+        assert_eq!(
+            stored.serve_query(v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts),
+            ledger.serve_query(v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts)
+        );
+
+        let mut loaded = stored.to_ledger().unwrap();
+        assert_eq!(loaded.hash_at(0, 0).unwrap(), root);
+
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_into_store() {
+        use snarked_ledger::tests::{ledger, run, source};
+
+        let dir = std::env::temp_dir().join(format!("ledger-store-sync-{}", std::process::id()));
+        let store = LedgerStore::open(&dir).unwrap();
+        let (mut full, root) = source(100, usize::MAX, 0);
+        let name = hash_name(&root).unwrap();
+        let mut expected = ledger(100, &[]);
+
+        // killed, the incomplete ledger is not visible
+        let (mut killed, _) = source(100, 6, 0);
+        let mut previous = ledger(120, &[3, 60]);
+        assert!(run(store.sync_diff(&mut killed, &root, &mut previous)).is_none());
+        assert!(store.ledger(&name).unwrap().is_none());
+
+        // continues from the written subtrees
+        let (mut stored, stats) = run(store.sync_diff(&mut full, &root, &mut previous))
+            .unwrap()
+            .unwrap();
+        assert!(stats.accounts_fetched <= 8 + 8 + 4);
+        assert_eq!(stored.hash_at(0, 0).unwrap(), root);
+        for (depth, pos) in [(32, 12), (33, 25), (34, 49), (35, 99), (20, 0), (1, 0)] {
+            assert_eq!(
+                stored.hash_at(depth, pos).unwrap(),
+                expected.hash_at(depth, pos).unwrap()
+            );
+        }
+        for index in 0..100 {
+            let account = stored.account_at(index).unwrap().unwrap();
+            assert_eq!(Some(account), expected.account_at(index).unwrap());
+        }
+        assert!(stored.account_at(100).unwrap().is_none());
+        assert_eq!(stored.num_accounts(), 100);
+        assert_eq!(store.hashes().unwrap(), [name]);
+
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
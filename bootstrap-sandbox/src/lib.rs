@@ -0,0 +1,3 @@
+//! The parts of the sandbox that other tools use without the network and the ledgers.
+
+pub mod merkle_path;
@@ -2,6 +2,13 @@
 
 mod client;
 mod snarked_ledger;
+mod snapshot;
+mod ledger_store;
+mod runtime_config;
+mod ledger_diff;
+mod account_proof;
+mod stake;
+mod ledger_files;
 mod bootstrap;
 mod check;
 mod archive_block;
@@ -9,26 +16,74 @@ mod archive_block;
 mod record;
 mod replay;
 
-use std::{env, path::PathBuf};
+use std::{fs::File, path::PathBuf, time::Duration};
 
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, PeerId};
 use libp2p_rpc_behaviour::BehaviourBuilder;
 use structopt::StructOpt;
-use mina_transport::ed25519::SecretKey;
+use mina_transport::{
+    address_book::AddressBook,
+    capture::Capture,
+    discovery::Discovery,
+    faults::FaultConfig,
+    gate::{Gate, GateConfig, IpNet},
+    network::Network,
+};
 
 #[derive(StructOpt)]
 struct Args {
     #[structopt(long, default_value = "target/default")]
     path: PathBuf,
-    #[structopt(
-        long,
-        default_value = "667b328bfc09ced12191d099f234575b006b6b193f5441a6fa744feacd9744db"
-    )]
-    chain_id: String,
+    /// One of `mainnet`, `devnet`, `berkeley`, or a TOML or JSON file describing the network
+    #[structopt(long, default_value = "berkeley")]
+    network: String,
+    /// The runtime config of the Mina node, its constants override the ones of the network
+    #[structopt(long)]
+    runtime_config: Option<PathBuf>,
+    /// Overrides the chain id of the network
+    #[structopt(long)]
+    chain_id: Option<String>,
+    /// Key file in the format of `mina libp2p generate-keypair`, the password is taken
+    /// from `MINA_LIBP2P_PASS`. Created if absent. `OPENMINA_P2P_SEC_KEY` takes precedence
+    #[structopt(long)]
+    key: Option<PathBuf>,
     #[structopt(long)]
     listen: Vec<Multiaddr>,
     #[structopt(long)]
     peer: Vec<Multiaddr>,
+    /// Dial the peers found in the DHT until there are this many connections
+    #[structopt(long, default_value = "8")]
+    target_connections: usize,
+    /// Connect only to these peers
+    #[structopt(long)]
+    allow_peer: Vec<PeerId>,
+    /// Never connect to these peers
+    #[structopt(long)]
+    deny_peer: Vec<PeerId>,
+    /// Connect only to addresses in these networks, like `10.0.0.0/8`
+    #[structopt(long)]
+    allow_ip: Vec<IpNet>,
+    /// Never connect to addresses in these networks
+    #[structopt(long)]
+    deny_ip: Vec<IpNet>,
+    #[structopt(long)]
+    max_connections_per_ip: Option<usize>,
+    #[structopt(long)]
+    max_connections: Option<usize>,
+    /// Directory where to store the decrypted traffic of each connection
+    #[structopt(long)]
+    capture: Option<PathBuf>,
+    /// JSON file describing latency, bandwidth, stalls and resets to inject into connections
+    #[structopt(long)]
+    faults: Option<PathBuf>,
+    /// JSON file with the known peers, loaded on start and updated while running.
+    /// The peers that were kept connected are dialed again
+    #[structopt(long)]
+    address_book: Option<PathBuf>,
+    /// Keep the ledgers in an embedded database in this directory, shared by all heights,
+    /// instead of a file per ledger in each height
+    #[structopt(long)]
+    ledger_store: Option<PathBuf>,
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -41,6 +96,9 @@ enum Command {
     Record {
         #[structopt(long)]
         bootstrap: bool,
+        /// Seconds to wait for a response before asking another peer
+        #[structopt(long, default_value = "30")]
+        timeout: u64,
     },
     Replay {
         height: u32,
@@ -60,6 +118,57 @@ enum Command {
         state: String,
     },
     ApplyArchive,
+    /// Rewrite the recorded ledgers of all heights as snapshots
+    ConvertLedgers,
+    /// Write a recorded ledger as the `ledger` of a Mina runtime config
+    ExportLedger {
+        height: u32,
+        hash: String,
+        /// The standard output if absent
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the accounts added, removed and modified between two ledger files as JSON
+    DiffLedgers {
+        before: PathBuf,
+        after: PathBuf,
+    },
+    /// Print the account with its index and merkle path as JSON
+    Account {
+        /// A ledger file, or a ledger hash in `--ledger-store`
+        ledger: String,
+        public_key: String,
+        /// The default token if absent
+        #[structopt(long)]
+        token: Option<String>,
+    },
+    /// Print the stake of the block producers of an epoch ledger, as JSON or CSV
+    StakeReport {
+        /// A ledger file, like `epoch_ledger.bin`, or a ledger hash in `--ledger-store`
+        ledger: String,
+        /// How many of the largest producers to print
+        #[structopt(long, default_value = "20")]
+        top: usize,
+        #[structopt(long)]
+        csv: bool,
+    },
+    /// Check that every ledger file in the directory is named by its merkle root
+    VerifyLedgers {
+        dir: PathBuf,
+    },
+    /// Print the hash and the number of accounts of every ledger in `--ledger-store`
+    ListLedgers,
+}
+
+/// The ledger with this hash in the store, otherwise the ledger file at this path.
+fn open_ledger(
+    ledger_store: Option<&ledger_store::LedgerStore>,
+    ledger: &str,
+) -> Box<dyn snarked_ledger::LedgerView> {
+    match ledger_store.and_then(|store| store.ledger(ledger).unwrap()) {
+        Some(stored) => Box::new(stored),
+        None => snapshot::open(ledger).unwrap(),
+    }
 }
 
 #[tokio::main]
@@ -68,30 +177,73 @@ async fn main() {
 
     let Args {
         path,
+        network,
+        runtime_config,
         chain_id,
+        key,
         listen,
-        peer,
+        mut peer,
+        target_connections,
+        allow_peer,
+        deny_peer,
+        allow_ip,
+        deny_ip,
+        max_connections_per_ip,
+        max_connections,
+        capture,
+        faults,
+        address_book,
+        ledger_store,
         cmd,
     } = Args::from_args();
 
-    let sk = env::var("OPENMINA_P2P_SEC_KEY")
-        .map(|key| {
-            let mut bytes = bs58::decode(key).with_check(Some(0x80)).into_vec().unwrap();
-            SecretKey::from_bytes(&mut bytes[1..]).unwrap()
-        })
-        .unwrap_or_else(|_| {
-            let mut bytes = rand::random::<[u8; 32]>();
-            log::info!(
-                "{}",
-                bs58::encode(&bytes).with_check_version(0x80).into_string()
-            );
-            let sk = SecretKey::from_bytes(&mut bytes).unwrap();
-            sk
-        });
-
-    let local_key: libp2p::identity::Keypair = mina_transport::ed25519::Keypair::from(sk).into();
+    let mut network = Network::select(&network).unwrap();
+    if let Some(path) = runtime_config {
+        network = network.with_runtime_config(path).unwrap();
+    }
+    let constraint_constants = network.constraint_constants.to_ledger().unwrap();
+    let chain_id = chain_id.unwrap_or(network.chain_id);
+    if peer.is_empty() {
+        peer = network.seeds;
+    }
+
+    let ledger_store = ledger_store.map(|path| ledger_store::LedgerStore::open(path).unwrap());
+
+    let local_key = mina_transport::keys::load_or_generate(key.as_deref()).unwrap();
     log::info!("{}", local_key.public().to_peer_id());
 
+    // the node under test connects to the replayer, no need to look for other peers
+    let target_connections = if matches!(cmd, Command::Replay { .. }) {
+        0
+    } else {
+        target_connections
+    };
+    // same for the address book, the replayer never dials
+    let address_book = match address_book {
+        Some(path) if !matches!(cmd, Command::Replay { .. }) => AddressBook::load(path).unwrap(),
+        _ => AddressBook::default(),
+    };
+    let mut discovery = Discovery::new(&local_key, target_connections);
+    for addr in &peer {
+        discovery.add_seed(addr);
+    }
+    let gate = Gate::new(GateConfig {
+        allow_peers: allow_peer.into_iter().collect(),
+        deny_peers: deny_peer.into_iter().collect(),
+        allow_ips: allow_ip,
+        deny_ips: deny_ip,
+        max_per_ip: max_connections_per_ip,
+        max_connections,
+    });
+
+    let mut builder = mina_transport::Builder::new(local_key, chain_id.as_bytes());
+    if let Some(path) = capture {
+        builder = builder.capture(Capture::new(path).unwrap());
+    }
+    if let Some(path) = faults {
+        builder = builder.faults(FaultConfig::load(path).unwrap());
+    }
+
     // let listen_on = [
     //     "/ip6/::/tcp/8302".parse().unwrap(),
     //     "/ip4/0.0.0.0/tcp/8302".parse().unwrap(),
@@ -108,14 +260,33 @@ async fn main() {
 
     match cmd {
         Command::Again { height } => {
-            bootstrap::again(&path, height).await;
+            bootstrap::again(&path, height, ledger_store.as_ref(), &constraint_constants).await;
         }
-        Command::Record { bootstrap } => {
-            let behaviour = BehaviourBuilder::default().build();
-            let swarm =
-                mina_transport::swarm(local_key, chain_id.as_bytes(), listen, peer, behaviour);
+        Command::Record { bootstrap, timeout } => {
+            use mina_p2p_messages::rpc::AnswerSyncLedgerQueryV2;
 
-            record::run(swarm, &path, bootstrap).await
+            let mut behaviour = BehaviourBuilder::default();
+            if ledger_store.is_some() {
+                behaviour = behaviour.register_method::<AnswerSyncLedgerQueryV2>();
+            }
+            let behaviour = mina_transport::Behaviour::new(discovery, behaviour.build())
+                .with_gate(gate)
+                .with_address_book(address_book);
+            let swarm = builder.build(listen, peer, behaviour);
+            let mut client = client::Client::new(swarm).with_timeout(Duration::from_secs(timeout));
+            // the peers may sync the ledgers that are already in the store
+            if let Some(store) = &ledger_store {
+                client = client.with_responder(store.clone());
+            }
+
+            record::run(
+                client,
+                &path,
+                bootstrap,
+                ledger_store.as_ref(),
+                &constraint_constants,
+            )
+            .await
         }
         Command::Replay { height } => {
             use mina_p2p_messages::rpc::{
@@ -131,24 +302,29 @@ async fn main() {
                 .register_method::<GetTransitionChainV2>()
                 .register_method::<GetTransitionChainProofV1ForV2>()
                 .build();
-            let swarm =
-                mina_transport::swarm(local_key, chain_id.as_bytes(), listen, [], behaviour);
+            let behaviour = mina_transport::Behaviour::new(discovery, behaviour)
+                .with_gate(gate)
+                .with_address_book(address_book);
+            let swarm = builder.build(listen, [], behaviour);
 
-            replay::run(swarm, &path, height).await
+            replay::run(swarm, &path, height, ledger_store.as_ref()).await
         }
         Command::Empty => {
             use libp2p::{futures::StreamExt, swarm::SwarmEvent};
             use libp2p_rpc_behaviour::{Event, Received};
             use mina_p2p_messages::{rpc::GetBestTipV2, rpc_kernel::RpcMethod};
+            use mina_transport::BehaviourEvent;
 
             let behaviour = BehaviourBuilder::default()
                 .register_method::<GetBestTipV2>()
                 .build();
-            let mut swarm =
-                mina_transport::swarm(local_key, chain_id.as_bytes(), listen, peer, behaviour);
+            let behaviour = mina_transport::Behaviour::new(discovery, behaviour)
+                .with_gate(gate)
+                .with_address_book(address_book);
+            let mut swarm = builder.build(listen, peer, behaviour);
             while let Some(event) = swarm.next().await {
                 match event {
-                    SwarmEvent::Behaviour((peer_id, event)) => match event {
+                    SwarmEvent::Behaviour(BehaviourEvent::Inner((peer_id, event))) => match event {
                         Event::Stream {
                             stream_id,
                             received: Received::Query { header, .. },
@@ -156,6 +332,7 @@ async fn main() {
                             (GetBestTipV2::NAME, GetBestTipV2::VERSION) => {
                                 swarm
                                     .behaviour_mut()
+                                    .inner
                                     .respond::<GetBestTipV2>(
                                         peer_id,
                                         stream_id,
@@ -183,6 +360,111 @@ async fn main() {
             check::test_graphql(&path, height, url, verbose);
         }
         Command::Archive { state } => archive_block::store(&path, state.parse().unwrap()),
-        Command::ApplyArchive => archive_block::run(&path),
+        Command::ApplyArchive => archive_block::run(&path, &constraint_constants),
+        Command::ConvertLedgers => {
+            for height in std::fs::read_dir(&path).unwrap() {
+                let height = height.unwrap().path();
+                let ledgers = height.join("ledgers");
+                let Ok(entries) = std::fs::read_dir(&ledgers) else {
+                    continue;
+                };
+                for entry in entries {
+                    let file = entry.unwrap().path();
+                    if snapshot::is_snapshot(&file).unwrap() {
+                        continue;
+                    }
+                    // not in `ledgers`, replay opens every file there
+                    let tmp = height.join("ledger.tmp");
+                    snapshot::convert(&file, &tmp).unwrap();
+                    std::fs::rename(tmp, &file).unwrap();
+                    log::info!("converted {}", file.display());
+                }
+            }
+        }
+        Command::ExportLedger {
+            height,
+            hash,
+            output,
+        } => {
+            let stored = ledger_store
+                .as_ref()
+                .and_then(|store| store.ledger(&hash).unwrap());
+            let mut ledger: Box<dyn snarked_ledger::LedgerView> = match stored {
+                Some(ledger) => Box::new(ledger),
+                None => {
+                    let ledgers = path.join(height.to_string()).join("ledgers");
+                    snapshot::open(ledgers.join(&hash)).unwrap()
+                }
+            };
+            let config = runtime_config::export(&mut *ledger).unwrap();
+            match output {
+                Some(output) => {
+                    serde_json::to_writer_pretty(File::create(output).unwrap(), &config)
+                }
+                None => serde_json::to_writer_pretty(std::io::stdout(), &config),
+            }
+            .unwrap();
+        }
+        Command::DiffLedgers { before, after } => {
+            let mut before = snapshot::open(before).unwrap();
+            let mut after = snapshot::open(after).unwrap();
+            let diff = ledger_diff::diff(&mut *before, &mut *after).unwrap();
+            serde_json::to_writer_pretty(std::io::stdout(), &diff).unwrap();
+        }
+        Command::Account {
+            ledger,
+            public_key,
+            token,
+        } => {
+            use mina_p2p_messages::v2;
+
+            let mut ledger = open_ledger(ledger_store.as_ref(), &ledger);
+            let public_key = mina_signer::CompressedPubKey::from_address(&public_key).unwrap();
+            let token_id = token.map(|token| {
+                let token = token.parse::<v2::TokenIdKeyHash>().unwrap();
+                mina_tree::TokenId(token.to_field())
+            });
+            let Some((index, account)) =
+                account_proof::find(&mut *ledger, &public_key, token_id.as_ref()).unwrap()
+            else {
+                log::error!("no such account");
+                return;
+            };
+            let path = account_proof::path(&mut *ledger, index).unwrap();
+            let ledger_hash = ledger.hash_at(0, 0).unwrap();
+            assert!(account_proof::verify(&account, &path, &ledger_hash));
+            let output = serde_json::json!({
+                "ledger_hash": ledger_hash,
+                "index": index,
+                "account": runtime_config::export_account(&account),
+                "path": path,
+            });
+            serde_json::to_writer_pretty(std::io::stdout(), &output).unwrap();
+        }
+        Command::StakeReport { ledger, top, csv } => {
+            let mut ledger = open_ledger(ledger_store.as_ref(), &ledger);
+            let report =
+                stake::report(&mut *ledger, top, network.active_slots_coefficient).unwrap();
+            if csv {
+                report.write_csv(std::io::stdout()).unwrap();
+            } else {
+                serde_json::to_writer_pretty(std::io::stdout(), &report).unwrap();
+            }
+        }
+        Command::VerifyLedgers { dir } => {
+            let ledgers = ledger_files::load_dir(&dir).unwrap();
+            for (name, ledger) in ledgers {
+                log::info!("{name} is ok, {} accounts", ledger.num);
+            }
+        }
+        Command::ListLedgers => {
+            use snarked_ledger::LedgerView;
+
+            let store = ledger_store.expect("needs `--ledger-store`");
+            for hash in store.hashes().unwrap() {
+                let ledger = store.ledger(&hash).unwrap().expect("just listed");
+                println!("{hash} {}", ledger.num_accounts());
+            }
+        }
     }
 }
@@ -0,0 +1,35 @@
+//! Check that a ledger includes an account, given the merkle path of the account.
+//!
+//! The path goes from the account up to the root, each entry is the hash of the sibling
+//! subtree and the side of the node on the path, as `MerklePath` in Mina.
+
+use mina_p2p_messages::v2;
+use mina_tree::{Account, TreeVersion, V2};
+use serde::{Deserialize, Serialize};
+
+/// The depth of the ledger, the length of a complete path.
+pub const DEPTH: u32 = 35;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathEntry {
+    /// The node is the left child, the hash is of the right one.
+    Left(v2::LedgerHash),
+    /// The node is the right child, the hash is of the left one.
+    Right(v2::LedgerHash),
+}
+
+/// Whether the ledger with this hash includes the account at the end of the path.
+pub fn verify(account: &Account, path: &[PathEntry], ledger_hash: &v2::LedgerHash) -> bool {
+    if path.len() != DEPTH as usize {
+        return false;
+    }
+    let root = path
+        .iter()
+        .enumerate()
+        .fold(account.hash(), |hash, (height, entry)| match entry {
+            PathEntry::Left(right) => V2::hash_node(height, hash, right.to_field()),
+            PathEntry::Right(left) => V2::hash_node(height, left.to_field(), hash),
+        });
+    root == ledger_hash.to_field()
+}
@@ -6,7 +6,6 @@ use std::{
 };
 
 use binprot::{BinProtRead, BinProtWrite};
-use libp2p::Swarm;
 use mina_p2p_messages::{
     rpc::{
         GetBestTipV2, WithHashV1, GetAncestryV2, GetStagedLedgerAuxAndPendingCoinbasesAtHashV2,
@@ -14,13 +13,54 @@ use mina_p2p_messages::{
     },
     v2,
 };
-use libp2p_rpc_behaviour::Behaviour;
+use mina_tree::scan_state::scan_state::ConstraintConstants;
 
-use super::{client::Client, bootstrap::Storage, snarked_ledger::SnarkedLedger};
+use super::{
+    client::Client,
+    bootstrap::Storage,
+    snarked_ledger::{LedgerView, SnarkedLedger},
+    snapshot,
+    ledger_store::LedgerStore,
+};
 
-pub async fn run(swarm: Swarm<Behaviour>, path_main: &Path, bootstrap: bool) {
-    let mut client = Client::new(swarm);
+/// As a snapshot file of this height.
+fn save_ledger(path: &Path, hash_str: &str, ledger: &mut SnarkedLedger) {
+    let file = File::create(path.join("ledgers").join(hash_str)).unwrap();
+    snapshot::store(ledger, file).unwrap();
+}
 
+/// Without a store the ledger is synced in memory, the progress is kept in the `.bin`
+/// and the checkpoint files of this height named after the ledger. The ledger that
+/// starts from scratch takes the accounts of the `base` when it can.
+async fn sync_in_memory(
+    client: &mut Client,
+    path: &Path,
+    name: &str,
+    hash: &v2::LedgerHash,
+    base: Option<&mut SnarkedLedger>,
+) -> SnarkedLedger {
+    let mut ledger = match File::open(path.join(format!("{name}.bin"))) {
+        Ok(file) => SnarkedLedger::load_bin(file).unwrap(),
+        Err(_) => SnarkedLedger::empty(),
+    }
+    .with_checkpoint(path.join(format!("{name}.checkpoint")));
+    match base {
+        Some(base) if ledger.num == 0 => ledger.sync_diff(client, hash, base).await.unwrap(),
+        _ => ledger.sync_new(client, hash).await.unwrap(),
+    };
+    ledger
+        .store_bin(File::create(path.join(format!("{name}.bin"))).unwrap())
+        .unwrap();
+    ledger
+}
+
+pub async fn run(
+    mut client: Client,
+    path_main: &Path,
+    bootstrap: bool,
+    ledger_store: Option<&LedgerStore>,
+    constraint_constants: &ConstraintConstants,
+) {
     fs::create_dir_all(&path_main).unwrap();
 
     let best_tip = client.rpc::<GetBestTipV2>(()).await.unwrap().unwrap();
@@ -57,32 +97,17 @@ pub async fn run(swarm: Swarm<Behaviour>, path_main: &Path, bootstrap: bool) {
 
     let snarked_protocol_state = best_tip.proof.1.header.protocol_state;
 
-    let mut epoch_ledger = match File::open(path.join("epoch_ledger.bin")) {
-        Ok(file) => SnarkedLedger::load_bin(file).unwrap(),
-        Err(_) => SnarkedLedger::empty(),
-    };
-    let next_epoch_ledger_hash = snarked_protocol_state
-        .body
-        .consensus_state
-        .next_epoch_data
-        .ledger
-        .hash
-        .clone();
-    let next_epoch_ledger_hash_str = match serde_json::to_value(&next_epoch_ledger_hash).unwrap() {
+    let hash_str = |hash: &v2::LedgerHash| match serde_json::to_value(hash).unwrap() {
         serde_json::Value::String(s) => s,
         _ => panic!(),
     };
-
-    epoch_ledger
-        .sync_new(&mut client, &next_epoch_ledger_hash)
-        .await;
-    epoch_ledger
-        .store_bin(File::create(path.join("ledgers").join(next_epoch_ledger_hash_str)).unwrap())
-        .unwrap();
-    epoch_ledger
-        .store_bin(File::create(path.join("epoch_ledger.bin")).unwrap())
-        .unwrap();
-
+    // the winners of the slots are in the staking ledger, or in the next epoch ledger
+    // once the blocks reach the next epoch
+    let consensus_state = &snarked_protocol_state.body.consensus_state;
+    let staking_ledger_hash = consensus_state.staking_epoch_data.ledger.hash.clone();
+    let staking_ledger_hash_str = hash_str(&staking_ledger_hash);
+    let next_epoch_ledger_hash = consensus_state.next_epoch_data.ledger.hash.clone();
+    let next_epoch_ledger_hash_str = hash_str(&next_epoch_ledger_hash);
     let snarked_ledger_hash = snarked_protocol_state
         .body
         .blockchain_state
@@ -90,24 +115,71 @@ pub async fn run(swarm: Swarm<Behaviour>, path_main: &Path, bootstrap: bool) {
         .target
         .first_pass_ledger
         .clone();
-    let snarked_ledger_hash_str = match serde_json::to_value(&snarked_ledger_hash).unwrap() {
-        serde_json::Value::String(s) => s,
-        _ => panic!(),
-    };
+    let snarked_ledger_hash_str = hash_str(&snarked_ledger_hash);
     log::info!("snarked_ledger_hash: {snarked_ledger_hash_str}");
-    let mut snarked_ledger = match File::open(path.join("current_ledger.bin")) {
-        Ok(file) => SnarkedLedger::load_bin(file).unwrap(),
-        Err(_) => SnarkedLedger::empty(),
+
+    // each ledger is the previous one with a few changes
+    let (staking_ledger, epoch_ledger, snarked_ledger) = match ledger_store {
+        Some(store) => {
+            // straight into the store, the sync that did not finish continues from
+            // the subtrees it has written
+            let (mut staking_ledger, _) = store
+                .sync_diff(
+                    &mut client,
+                    &staking_ledger_hash,
+                    &mut SnarkedLedger::empty(),
+                )
+                .await
+                .unwrap();
+            let (mut epoch_ledger, _) = store
+                .sync_diff(&mut client, &next_epoch_ledger_hash, &mut staking_ledger)
+                .await
+                .unwrap();
+            store
+                .sync_diff(&mut client, &snarked_ledger_hash, &mut epoch_ledger)
+                .await
+                .unwrap();
+            (
+                Box::new(staking_ledger) as Box<dyn LedgerView>,
+                Box::new(epoch_ledger) as Box<dyn LedgerView>,
+                None,
+            )
+        }
+        None => {
+            let mut staking_ledger = sync_in_memory(
+                &mut client,
+                &path,
+                "staking_ledger",
+                &staking_ledger_hash,
+                None,
+            )
+            .await;
+            save_ledger(&path, &staking_ledger_hash_str, &mut staking_ledger);
+            let mut epoch_ledger = sync_in_memory(
+                &mut client,
+                &path,
+                "epoch_ledger",
+                &next_epoch_ledger_hash,
+                Some(&mut staking_ledger),
+            )
+            .await;
+            save_ledger(&path, &next_epoch_ledger_hash_str, &mut epoch_ledger);
+            let mut snarked_ledger = sync_in_memory(
+                &mut client,
+                &path,
+                "current_ledger",
+                &snarked_ledger_hash,
+                Some(&mut epoch_ledger),
+            )
+            .await;
+            save_ledger(&path, &snarked_ledger_hash_str, &mut snarked_ledger);
+            (
+                Box::new(staking_ledger) as Box<dyn LedgerView>,
+                Box::new(epoch_ledger) as Box<dyn LedgerView>,
+                Some(snarked_ledger),
+            )
+        }
     };
-    snarked_ledger
-        .sync_new(&mut client, &snarked_ledger_hash)
-        .await;
-    snarked_ledger
-        .store_bin(File::create(path.join("ledgers").join(snarked_ledger_hash_str)).unwrap())
-        .unwrap();
-    snarked_ledger
-        .store_bin(File::create(path.join("current_ledger.bin")).unwrap())
-        .unwrap();
 
     let expected_hash = snarked_protocol_state
         .body
@@ -145,12 +217,40 @@ pub async fn run(swarm: Swarm<Behaviour>, path_main: &Path, bootstrap: bool) {
     )
     .await;
 
+    for (peer_id, score) in client.scores() {
+        log::info!("peer {peer_id}: {score:?}");
+    }
+    for peer_id in client.blamed() {
+        log::warn!("peer {peer_id} was blamed");
+    }
+
     if bootstrap {
-        let mut storage = Storage::new(snarked_ledger.inner, info, expected_hash);
+        // the staged ledger is built on top of the snarked ledger in memory
+        let snarked_ledger = match (snarked_ledger, ledger_store) {
+            (Some(ledger), _) => ledger,
+            (None, Some(store)) => store
+                .ledger(&snarked_ledger_hash_str)
+                .unwrap()
+                .expect("just synced")
+                .to_ledger()
+                .unwrap(),
+            (None, None) => unreachable!(),
+        };
+        let mut storage = Storage::new(
+            constraint_constants.clone(),
+            snarked_ledger.inner,
+            info,
+            expected_hash,
+        )
+        .unwrap_or_else(|err| panic!("{err}"))
+        .with_ledger(staking_ledger_hash_str, staking_ledger)
+        .with_ledger(next_epoch_ledger_hash_str, epoch_ledger);
 
         let mut prev_protocol_state = snarked_protocol_state;
         while let Some(block) = blocks.pop_back() {
-            storage.apply_block(&block, &prev_protocol_state);
+            if let Err(err) = storage.apply_block(&block, &prev_protocol_state) {
+                panic!("{err}");
+            }
             prev_protocol_state = block.header.protocol_state.clone();
         }
     }
@@ -182,6 +282,7 @@ async fn download_blocks(
     };
 
     log::info!("need blocks {}..{head_height}", snarked_height + 1);
+    let mut proofs = vec![];
     for i in ((snarked_height + 1)..head_height).rev() {
         let last_protocol_state = &blocks.back().unwrap().header.protocol_state;
         let this_hash = &last_protocol_state.previous_state_hash;
@@ -205,17 +306,27 @@ async fn download_blocks(
                 .unwrap();
             let mut file = File::create(dir.join(this_hash.to_string())).unwrap();
             new[0].binprot_write(&mut file).unwrap();
-            if let Ok(new_proof) = engine
-                .rpc::<GetTransitionChainProofV1ForV2>(this_hash.0.clone())
-                .await
-            {
-                let mut file = File::create(dir.join(format!("proof_{this_hash}"))).unwrap();
-                new_proof.binprot_write(&mut file).unwrap();
-            }
+            proofs.push((dir, this_hash.clone()));
             new[0].clone()
         };
         blocks.push_back(new);
     }
+
+    // the proofs don't depend on each other, ask several peers at once
+    log::info!("downloading {} proofs", proofs.len());
+    let queries = proofs.iter().map(|(_, hash)| hash.0.clone());
+    let results = engine
+        .rpc_many::<GetTransitionChainProofV1ForV2, _>(queries)
+        .await;
+    for ((dir, hash), result) in proofs.into_iter().zip(results) {
+        match result {
+            Ok(proof) => {
+                let mut file = File::create(dir.join(format!("proof_{hash}"))).unwrap();
+                proof.binprot_write(&mut file).unwrap();
+            }
+            Err(err) => log::warn!("cannot download proof of {hash}, {err}"),
+        }
+    }
     let file = File::create(dir.join("table.json")).unwrap();
     serde_json::to_writer(file, &table).unwrap();
     log::info!("have blocks {}..{head_height}", snarked_height + 1);
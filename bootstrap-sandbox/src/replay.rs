@@ -11,14 +11,21 @@ use mina_p2p_messages::{
         AnswerSyncLedgerQueryV2, GetTransitionChainV2, GetTransitionChainProofV1ForV2,
     },
     rpc_kernel::{RpcMethod, QueryHeader, QueryPayload, RpcResult},
+    core::Info,
     v2,
 };
 use binprot::BinProtRead;
 use libp2p_rpc_behaviour::{Event, Received, Behaviour};
+use mina_transport::BehaviourEvent;
 
-use super::snarked_ledger::SnarkedLedger;
+use super::{snarked_ledger::LedgerView, snapshot, ledger_store::LedgerStore};
 
-pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height: u32) {
+pub async fn run(
+    mut swarm: libp2p::Swarm<mina_transport::Behaviour<Behaviour>>,
+    path_main: &Path,
+    height: u32,
+    ledger_store: Option<&LedgerStore>,
+) {
     let path_blocks = path_main.join("blocks");
     let path = path_main.join(height.to_string());
 
@@ -32,11 +39,10 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
     type T = GetStagedLedgerAuxAndPendingCoinbasesAtHashV2;
     let staged_ledger_aux = <T as RpcMethod>::Response::binprot_read(&mut file).unwrap();
 
-    let mut ledgers = BTreeMap::new();
+    let mut ledgers = BTreeMap::<_, Box<dyn LedgerView>>::new();
     for entry in fs::read_dir(path.join("ledgers")).unwrap() {
         let entry = entry.unwrap();
-        let file = File::open(entry.path()).unwrap();
-        let ledger = SnarkedLedger::load_bin(file).unwrap();
+        let ledger = snapshot::open(entry.path()).unwrap();
         ledgers.insert(entry.file_name().to_str().unwrap().to_string(), ledger);
     }
 
@@ -58,21 +64,24 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
                     endpoint.get_remote_address()
                 );
             }
-            SwarmEvent::Behaviour((peer_id, Event::ConnectionEstablished)) => {
+            SwarmEvent::Behaviour(BehaviourEvent::Inner((
+                peer_id,
+                Event::ConnectionEstablished,
+            ))) => {
                 peers.insert(peer_id);
                 log::info!("new connection {peer_id}");
             }
-            SwarmEvent::Behaviour((peer_id, Event::ConnectionClosed)) => {
+            SwarmEvent::Behaviour(BehaviourEvent::Inner((peer_id, Event::ConnectionClosed))) => {
                 log::info!("connection closed {peer_id}");
                 peers.remove(&peer_id);
             }
-            SwarmEvent::Behaviour((
+            SwarmEvent::Behaviour(BehaviourEvent::Inner((
                 peer_id,
                 Event::Stream {
                     stream_id,
                     received,
                 },
-            )) => match received {
+            ))) => match received {
                 Received::HandshakeDone => {
                     log::info!("new stream {peer_id} {stream_id:?}");
                 }
@@ -90,6 +99,7 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
                         (GetBestTipV2::NAME, GetBestTipV2::VERSION) => {
                             swarm
                                 .behaviour_mut()
+                                .inner
                                 .respond::<GetBestTipV2>(
                                     peer_id,
                                     stream_id,
@@ -101,6 +111,7 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
                         (GetAncestryV2::NAME, GetAncestryV2::VERSION) => {
                             swarm
                                 .behaviour_mut()
+                                .inner
                                 .respond::<GetAncestryV2>(
                                     peer_id,
                                     stream_id,
@@ -122,12 +133,26 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
                                 _ => panic!(),
                             };
 
-                            let ledger = ledgers.get_mut(&hash_str).unwrap();
-                            let response = ledger.serve_query(query);
+                            // the stored ledgers are opened when first asked
+                            if !ledgers.contains_key(&hash_str) {
+                                let stored =
+                                    ledger_store.and_then(|store| store.ledger(&hash_str).unwrap());
+                                if let Some(ledger) = stored {
+                                    ledgers.insert(hash_str.clone(), Box::new(ledger));
+                                }
+                            }
+                            let response = match ledgers.get_mut(&hash_str) {
+                                Some(ledger) => ledger.serve_query(query),
+                                None => {
+                                    let msg = format!("unknown ledger {hash_str}");
+                                    Err(Info::CouldNotConstruct(msg.as_str().into()))
+                                }
+                            };
 
                             swarm
                                 .behaviour_mut()
-                                .respond::<T>(peer_id, stream_id, id, Ok(RpcResult(Ok(response))))
+                                .inner
+                                .respond::<T>(peer_id, stream_id, id, Ok(RpcResult(response)))
                                 .unwrap();
                         }
                         (
@@ -135,6 +160,7 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
                             GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::VERSION,
                         ) => swarm
                             .behaviour_mut()
+                            .inner
                             .respond::<GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(
                                 peer_id,
                                 stream_id,
@@ -175,6 +201,7 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
                                 .collect();
                             swarm
                                 .behaviour_mut()
+                                .inner
                                 .respond::<T>(peer_id, stream_id, id, Ok(Some(response)))
                                 .unwrap();
                             // if contains_last {
@@ -205,11 +232,16 @@ pub async fn run(mut swarm: libp2p::Swarm<Behaviour>, path_main: &Path, height:
 
                             swarm
                                 .behaviour_mut()
+                                .inner
                                 .respond::<T>(peer_id, stream_id, id, Ok(response))
                                 .unwrap();
                         }
                         (name, version) => {
-                            log::warn!("TODO: unhandled {name}, {version}");
+                            log::warn!("unhandled {name}, {version}");
+                            swarm
+                                .behaviour_mut()
+                                .inner
+                                .respond_unimplemented(peer_id, stream_id, id, name, version);
                         }
                     }
                 }
@@ -0,0 +1,437 @@
+//! The `ledger.accounts` of a Mina runtime config, the genesis ledger of a network.
+//!
+//! [`export`] writes any ledger in this format, [`import`] reads it back, the accounts
+//! keep their order, so the imported ledger has the same merkle root.
+
+use std::borrow::Cow;
+
+use ark_ff::PrimeField;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use binprot::{BinProtRead, BinProtWrite};
+use mina_curves::pasta::Fp;
+use mina_p2p_messages::v2;
+use mina_signer::CompressedPubKey;
+use mina_tree::{
+    scan_state::currency::{Amount, Balance, Nonce, Slot, SlotSpan},
+    Account, AuthRequired, ReceiptChainHash, Timing, TokenId, VerificationKey, VotingFor,
+    ZkAppAccount,
+};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use super::snarked_ledger::{self, LedgerView};
+
+#[derive(Serialize, Deserialize)]
+struct Timed {
+    initial_minimum_balance: String,
+    cliff_time: [String; 2],
+    cliff_amount: String,
+    vesting_period: [String; 2],
+    vesting_increment: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Permissions {
+    edit_state: String,
+    access: String,
+    send: String,
+    receive: String,
+    set_delegate: String,
+    set_permissions: String,
+    set_verification_key: String,
+    set_zkapp_uri: String,
+    edit_action_state: String,
+    set_token_symbol: String,
+    increment_nonce: String,
+    set_voting_for: String,
+    set_timing: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Zkapp {
+    app_state: Vec<String>,
+    /// Base64 of the binprot verification key.
+    verification_key: Option<String>,
+    zkapp_version: u32,
+    action_state: Vec<String>,
+    last_action_slot: u32,
+    proved_state: bool,
+    zkapp_uri: String,
+}
+
+/// The amount in mina with all nine decimals.
+fn mina_string(nanomina: u64) -> String {
+    format!(
+        "{}.{:09}",
+        nanomina / 1_000_000_000,
+        nanomina % 1_000_000_000
+    )
+}
+
+fn balance(mina: &str) -> Balance {
+    let mina = if !mina.contains('.') {
+        Cow::Owned(format!("{mina}.000000000"))
+    } else {
+        Cow::Borrowed(mina)
+    };
+    Balance::of_mina_string_exn(&mina)
+}
+
+fn field_string(field: &Fp) -> String {
+    BigUint::from(field.into_repr()).to_string()
+}
+
+fn field(decimal: &str) -> Fp {
+    decimal.parse().expect("decimal field element")
+}
+
+fn auth_str(auth: &AuthRequired) -> String {
+    match auth {
+        AuthRequired::None => "none",
+        AuthRequired::Either => "either",
+        AuthRequired::Proof => "proof",
+        AuthRequired::Signature => "signature",
+        AuthRequired::Impossible => "impossible",
+        AuthRequired::Both => "both",
+    }
+    .to_owned()
+}
+
+fn auth(s: &str) -> AuthRequired {
+    match s {
+        "none" => AuthRequired::None,
+        "either" => AuthRequired::Either,
+        "proof" => AuthRequired::Proof,
+        "signature" => AuthRequired::Signature,
+        "impossible" => AuthRequired::Impossible,
+        "both" => AuthRequired::Both,
+        _ => panic!("unknown auth required {s}"),
+    }
+}
+
+impl From<&mina_tree::Permissions<AuthRequired>> for Permissions {
+    fn from(value: &mina_tree::Permissions<AuthRequired>) -> Self {
+        Permissions {
+            edit_state: auth_str(&value.edit_state),
+            access: auth_str(&value.access),
+            send: auth_str(&value.send),
+            receive: auth_str(&value.receive),
+            set_delegate: auth_str(&value.set_delegate),
+            set_permissions: auth_str(&value.set_permissions),
+            set_verification_key: auth_str(&value.set_verification_key),
+            set_zkapp_uri: auth_str(&value.set_zkapp_uri),
+            edit_action_state: auth_str(&value.edit_action_state),
+            set_token_symbol: auth_str(&value.set_token_symbol),
+            increment_nonce: auth_str(&value.increment_nonce),
+            set_voting_for: auth_str(&value.set_voting_for),
+            set_timing: auth_str(&value.set_timing),
+        }
+    }
+}
+
+impl From<&Permissions> for mina_tree::Permissions<AuthRequired> {
+    fn from(value: &Permissions) -> Self {
+        mina_tree::Permissions {
+            edit_state: auth(&value.edit_state),
+            access: auth(&value.access),
+            send: auth(&value.send),
+            receive: auth(&value.receive),
+            set_delegate: auth(&value.set_delegate),
+            set_permissions: auth(&value.set_permissions),
+            set_verification_key: auth(&value.set_verification_key),
+            set_zkapp_uri: auth(&value.set_zkapp_uri),
+            edit_action_state: auth(&value.edit_action_state),
+            set_token_symbol: auth(&value.set_token_symbol),
+            increment_nonce: auth(&value.increment_nonce),
+            set_voting_for: auth(&value.set_voting_for),
+            set_timing: auth(&value.set_timing),
+        }
+    }
+}
+
+impl From<&ZkAppAccount> for Zkapp {
+    fn from(value: &ZkAppAccount) -> Self {
+        let verification_key = value.verification_key.as_ref().map(|vk| {
+            let mut bytes = vec![];
+            v2::MinaBaseVerificationKeyWireStableV1::from(vk)
+                .binprot_write(&mut bytes)
+                .expect("write to vec");
+            STANDARD.encode(bytes)
+        });
+        Zkapp {
+            app_state: value.app_state.iter().map(field_string).collect(),
+            verification_key,
+            zkapp_version: value.zkapp_version,
+            action_state: value.action_state.iter().map(field_string).collect(),
+            last_action_slot: value.last_action_slot.as_u32(),
+            proved_state: value.proved_state,
+            zkapp_uri: value.zkapp_uri.to_string(),
+        }
+    }
+}
+
+impl From<&Zkapp> for ZkAppAccount {
+    fn from(value: &Zkapp) -> Self {
+        let verification_key = value.verification_key.as_ref().map(|vk| {
+            let bytes = STANDARD.decode(vk).expect("base64 verification key");
+            let vk = v2::MinaBaseVerificationKeyWireStableV1::binprot_read(&mut bytes.as_slice())
+                .expect("binprot verification key");
+            VerificationKey::from(&vk)
+        });
+        let fields = |list: &[String]| -> Vec<Fp> { list.iter().map(|s| field(s)).collect() };
+        ZkAppAccount {
+            app_state: fields(&value.app_state)
+                .try_into()
+                .expect("8 app state fields"),
+            verification_key,
+            zkapp_version: value.zkapp_version,
+            action_state: fields(&value.action_state)
+                .try_into()
+                .expect("5 action state fields"),
+            last_action_slot: Slot::from_u32(value.last_action_slot),
+            proved_state: value.proved_state,
+            zkapp_uri: value.zkapp_uri.clone().into(),
+        }
+    }
+}
+
+/// The account as an entry of `ledger.accounts`.
+pub fn export_account(account: &Account) -> serde_json::Value {
+    let mut value = serde_json::Map::new();
+    let mut insert = |key: &str, v: serde_json::Value| {
+        value.insert(key.to_owned(), v);
+    };
+
+    insert("pk", account.public_key.into_address().into());
+    insert("balance", mina_string(account.balance.as_u64()).into());
+    // `null` is no delegate, absent is the account itself
+    let delegate = account
+        .delegate
+        .as_ref()
+        .map(CompressedPubKey::into_address);
+    insert("delegate", delegate.into());
+    if account.token_id != TokenId::default() {
+        let token = v2::TokenIdKeyHash::from(v2::MinaBaseAccountIdDigestStableV1(
+            account.token_id.0.into(),
+        ));
+        insert("token", token.to_string().into());
+    }
+    let token_symbol = account.token_symbol.to_string();
+    if !token_symbol.is_empty() {
+        insert("token_symbol", token_symbol.into());
+    }
+    insert("nonce", account.nonce.as_u32().to_string().into());
+    let receipt_chain_hash = v2::ReceiptChainHash::from(v2::MinaBaseReceiptChainHashStableV1(
+        account.receipt_chain_hash.0.into(),
+    ));
+    insert("receipt_chain_hash", receipt_chain_hash.to_string().into());
+    let voting_for = v2::StateHash::from(v2::DataHashLibStateHashStableV1(
+        account.voting_for.0.into(),
+    ));
+    insert("voting_for", voting_for.to_string().into());
+    if let Timing::Timed {
+        initial_minimum_balance,
+        cliff_time,
+        cliff_amount,
+        vesting_period,
+        vesting_increment,
+    } = &account.timing
+    {
+        let timed = Timed {
+            initial_minimum_balance: mina_string(initial_minimum_balance.as_u64()),
+            cliff_time: ["Since_genesis".to_owned(), cliff_time.as_u32().to_string()],
+            cliff_amount: mina_string(cliff_amount.as_u64()),
+            vesting_period: [
+                "Global_slot_span".to_owned(),
+                vesting_period.as_u32().to_string(),
+            ],
+            vesting_increment: mina_string(vesting_increment.as_u64()),
+        };
+        insert("timing", serde_json::to_value(timed).expect("plain struct"));
+    }
+    let permissions = Permissions::from(&account.permissions);
+    insert(
+        "permissions",
+        serde_json::to_value(permissions).expect("plain struct"),
+    );
+    if let Some(zkapp) = &account.zkapp {
+        let zkapp = serde_json::to_value(Zkapp::from(zkapp)).expect("plain struct");
+        insert("zkapp", zkapp);
+    }
+
+    value.into()
+}
+
+/// The accounts of the ledger, in the order of their indices, as the `ledger` of
+/// a runtime config.
+pub fn export<L>(ledger: &mut L) -> Result<serde_json::Value, snarked_ledger::Error>
+where
+    L: LedgerView + ?Sized,
+{
+    let mut accounts = vec![];
+    for index in 0..(ledger.num_accounts() as u64) {
+        if let Some(account) = ledger.account_at(index)? {
+            accounts.push(export_account(&account));
+        }
+    }
+    Ok(serde_json::json!({ "ledger": { "accounts": accounts } }))
+}
+
+fn import_account(mut value: serde_json::Value) -> Account {
+    let account_value = value.as_object_mut().unwrap();
+
+    let mut account = Account::empty();
+    account.public_key =
+        CompressedPubKey::from_address(account_value.remove("pk").unwrap().as_str().unwrap())
+            .unwrap();
+    if let Some(b) = account_value.remove("balance") {
+        account.balance = balance(b.as_str().unwrap());
+    }
+    account.delegate = match account_value.remove("delegate") {
+        Some(serde_json::Value::String(delegate)) => {
+            Some(CompressedPubKey::from_address(&delegate).unwrap())
+        }
+        Some(_) => None,
+        None => Some(account.public_key.clone()),
+    };
+    if let Some(token) = account_value.remove("token") {
+        let token = token
+            .as_str()
+            .unwrap()
+            .parse::<v2::TokenIdKeyHash>()
+            .unwrap();
+        account.token_id = TokenId(token.to_field());
+    }
+    if let Some(token_symbol) = account_value.remove("token_symbol") {
+        account.token_symbol = token_symbol.as_str().unwrap().to_owned().into();
+    }
+    if let Some(nonce) = account_value.remove("nonce") {
+        account.nonce = Nonce::from_u32(nonce.as_str().unwrap().parse().unwrap());
+    }
+    if let Some(hash) = account_value.remove("receipt_chain_hash") {
+        let hash = hash
+            .as_str()
+            .unwrap()
+            .parse::<v2::ReceiptChainHash>()
+            .unwrap();
+        account.receipt_chain_hash = ReceiptChainHash(hash.to_field());
+    }
+    if let Some(hash) = account_value.remove("voting_for") {
+        let hash = hash.as_str().unwrap().parse::<v2::StateHash>().unwrap();
+        account.voting_for = VotingFor(hash.to_field());
+    }
+    if let Some(timing) = account_value.remove("timing") {
+        let Timed {
+            initial_minimum_balance,
+            cliff_time,
+            cliff_amount,
+            vesting_period,
+            vesting_increment,
+        } = serde_json::from_value(timing).unwrap();
+
+        account.timing = Timing::Timed {
+            initial_minimum_balance: balance(&initial_minimum_balance),
+            cliff_time: Slot::from_u32(cliff_time[1].parse().unwrap()),
+            cliff_amount: balance(&cliff_amount).to_amount(),
+            vesting_period: SlotSpan::from_u32(vesting_period[1].parse().unwrap()),
+            vesting_increment: balance(&vesting_increment).to_amount(),
+        };
+    }
+    if let Some(permissions) = account_value.remove("permissions") {
+        let permissions = serde_json::from_value::<Permissions>(permissions).unwrap();
+        account.permissions = (&permissions).into();
+    }
+    if let Some(zkapp) = account_value.remove("zkapp") {
+        let zkapp = serde_json::from_value::<Zkapp>(zkapp).unwrap();
+        account.zkapp = Some((&zkapp).into());
+    }
+    account_value.remove("sk");
+
+    assert!(account_value.is_empty(), "unknown fields {account_value:?}");
+
+    account
+}
+
+/// The accounts of the runtime config, `None` if it has no `ledger.accounts`.
+pub fn import(config: &serde_json::Value) -> Option<Vec<Account>> {
+    let accounts = config
+        .as_object()?
+        .get("ledger")?
+        .as_object()?
+        .get("accounts")?
+        .as_array()?;
+    Some(accounts.iter().cloned().map(import_account).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_tree::{AccountIndex, BaseLedger, Database, Mask};
+
+    use super::{super::snarked_ledger::SnarkedLedger, *};
+
+    #[test]
+    fn round_trip() {
+        let public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+
+        let mut plain = Account::empty();
+        plain.public_key = public_key.clone();
+        plain.balance = Balance::of_nanomina_int_exn(1_000_000_000_123);
+        plain.delegate = Some(public_key.clone());
+
+        let mut timed = plain.clone();
+        timed.public_key = CompressedPubKey {
+            x: public_key.x,
+            is_odd: !public_key.is_odd,
+        };
+        timed.nonce = Nonce::from_u32(17);
+        timed.receipt_chain_hash = ReceiptChainHash(Fp::from(3u64));
+        timed.timing = Timing::Timed {
+            initial_minimum_balance: Balance::of_nanomina_int_exn(500_000_000_000),
+            cliff_time: Slot::from_u32(100),
+            cliff_amount: Amount::from_u64(5),
+            vesting_period: SlotSpan::from_u32(10),
+            vesting_increment: Amount::from_u64(1_000_000_000),
+        };
+
+        let mut zkapp = plain.clone();
+        zkapp.token_id = TokenId(Fp::from(7u64));
+        zkapp.token_symbol = "TOKEN".to_owned().into();
+        zkapp.delegate = None;
+        zkapp.voting_for = VotingFor(Fp::from(11u64));
+        zkapp.permissions.edit_state = AuthRequired::Proof;
+        zkapp.permissions.send = AuthRequired::Either;
+        let mut state = ZkAppAccount::default();
+        state.app_state[0] = Fp::from(42u64);
+        state.app_state[7] = -Fp::from(1u64);
+        state.zkapp_version = 1;
+        state.last_action_slot = Slot::from_u32(9);
+        state.proved_state = true;
+        state.zkapp_uri = "https://example.com".to_owned().into();
+        zkapp.zkapp = Some(state);
+
+        let accounts = vec![plain, timed, zkapp];
+        let mut ledger = SnarkedLedger::empty();
+        for (i, account) in accounts.iter().enumerate() {
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i as u64), Box::new(account.clone()))
+                .unwrap();
+        }
+        ledger.num = accounts.len() as u32;
+
+        let text = serde_json::to_string(&export(&mut ledger).unwrap()).unwrap();
+        let imported = import(&serde_json::from_str(&text).unwrap()).unwrap();
+        assert_eq!(imported, accounts);
+
+        let mut inner = Mask::new_root(Database::create(35));
+        for account in imported {
+            inner.get_or_create_account(account.id(), account).unwrap();
+        }
+        let root =
+            v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(inner.merkle_root().into()));
+        assert_eq!(root, ledger.hash_at(0, 0).unwrap());
+    }
+}
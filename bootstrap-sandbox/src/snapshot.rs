@@ -0,0 +1,419 @@
+//! Ledger snapshot, unlike [`SnarkedLedger::store_bin`] it keeps the account indices and
+//! the inner hashes, so a snapshot is served without computing the merkle tree.
+//!
+//! The layout, the integers are little endian:
+//!
+//! - `MINASNAP` and the version, `u32`;
+//! - the number of accounts, `u32`, and the top hash, binprot `Option<LedgerHash>`;
+//! - the hashes of the empty subtrees at each depth, binprot `LedgerHash`, 36 of them;
+//! - the hashes of the subtrees that have accounts, the count `u64`, then the depth `u8`,
+//!   the position `u64` and the binprot `LedgerHash` of each;
+//! - the accounts table, the count `u64`, then the index `u64`, the offset of the account
+//!   in the data `u64` and its length `u32` of each;
+//! - the data, binprot accounts;
+//! - the CRC-32 of everything after the version, `u32`.
+//!
+//! [`Snapshot::open`] checks the checksum and reads everything but the data, the accounts
+//! are read from the file when asked.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use binprot::{BinProtRead, BinProtWrite};
+use mina_p2p_messages::v2;
+use mina_tree::{Account, AccountIndex, Address, BaseLedger, Database, Mask};
+use thiserror::Error;
+
+use super::snarked_ledger::{self, LedgerView, SnarkedLedger};
+
+pub const MAGIC: &[u8; 8] = b"MINASNAP";
+pub const VERSION: u32 = 1;
+
+const DEPTH: u32 = 35;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Binprot(#[from] binprot::Error),
+    #[error("unsupported snapshot version {0}")]
+    Version(u32),
+    #[error("checksum mismatch, the snapshot is damaged")]
+    Checksum,
+    #[error("ledger: {0}")]
+    Ledger(String),
+}
+
+/// Updates the checksum with everything written.
+struct Checksum<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W> Write for Checksum<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The hashes of the empty subtrees, from the root down to the accounts.
+pub fn empty_hashes() -> Result<Vec<v2::LedgerHash>, Error> {
+    let mut empty = Mask::new_root(Database::create(DEPTH as _));
+    (0..=DEPTH)
+        .map(|depth| {
+            let addr = Address::from_index(AccountIndex(0), depth as _);
+            let hash = empty.get_inner_hash_at_addr(addr).map_err(Error::Ledger)?;
+            Ok(v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(
+                hash.into(),
+            )))
+        })
+        .collect()
+}
+
+/// Write the ledger as a snapshot.
+pub fn store<W>(ledger: &mut SnarkedLedger, writer: W) -> Result<(), Error>
+where
+    W: Write,
+{
+    let mut writer = Checksum {
+        inner: writer,
+        hasher: crc32fast::Hasher::new(),
+    };
+    writer.inner.write_all(MAGIC)?;
+    writer.inner.write_all(&VERSION.to_le_bytes())?;
+
+    writer.write_all(&ledger.num.to_le_bytes())?;
+    ledger.top_hash.binprot_write(&mut writer)?;
+    for hash in empty_hashes()? {
+        hash.binprot_write(&mut writer)?;
+    }
+
+    let len = ledger.inner.num_accounts() as u64;
+    let mut hashes = vec![];
+    for depth in 0..=DEPTH {
+        // the subtrees at this depth that have accounts
+        let height = DEPTH - depth;
+        let count = (len + (1 << height) - 1) >> height;
+        for pos in 0..count {
+            let hash = ledger.hash_at(depth, pos).map_err(into_snapshot)?;
+            hashes.push((depth as u8, pos, hash));
+        }
+    }
+    writer.write_all(&(hashes.len() as u64).to_le_bytes())?;
+    for (depth, pos, hash) in hashes {
+        writer.write_all(&[depth])?;
+        writer.write_all(&pos.to_le_bytes())?;
+        hash.binprot_write(&mut writer)?;
+    }
+
+    let mut table = vec![];
+    let mut data = vec![];
+    for index in 0..len {
+        if let Some(account) = ledger.account_at(index).map_err(into_snapshot)? {
+            let offset = data.len();
+            account.binprot_write(&mut data)?;
+            table.push((index, offset as u64, (data.len() - offset) as u32));
+        }
+    }
+    writer.write_all(&(table.len() as u64).to_le_bytes())?;
+    for (index, offset, length) in table {
+        writer.write_all(&index.to_le_bytes())?;
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&length.to_le_bytes())?;
+    }
+    writer.write_all(&data)?;
+
+    let checksum = writer.hasher.finalize();
+    writer.inner.write_all(&checksum.to_le_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn into_snapshot(err: snarked_ledger::Error) -> Error {
+    match err {
+        snarked_ledger::Error::Snapshot(err) => err,
+        err => Error::Ledger(err.to_string()),
+    }
+}
+
+/// A snapshot file, the accounts are read on demand.
+pub struct Snapshot {
+    file: File,
+    num: u32,
+    top_hash: Option<v2::LedgerHash>,
+    empty: Vec<v2::LedgerHash>,
+    hashes: BTreeMap<(u32, u64), v2::LedgerHash>,
+    /// The offset and the length of each account in the file.
+    accounts: BTreeMap<u64, (u64, u32)>,
+}
+
+fn read_array<R, const N: usize>(reader: &mut R) -> io::Result<[u8; N]>
+where
+    R: Read,
+{
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Whether the file starts with [`MAGIC`], otherwise it is in the format of
+/// [`SnarkedLedger::store_bin`].
+pub fn is_snapshot<P>(path: P) -> io::Result<bool>
+where
+    P: AsRef<Path>,
+{
+    let mut magic = [0; 8];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+impl Snapshot {
+    pub fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let header = (MAGIC.len() + 4) as u64;
+        if len < header + 4 || read_array::<_, 8>(&mut file)? != *MAGIC {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        let version = u32::from_le_bytes(read_array(&mut file)?);
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut body = BufReader::new(&mut file).take(len - header - 4);
+        let mut buf = [0; 0x10000];
+        loop {
+            let n = body.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let expected = u32::from_le_bytes(read_array(&mut body.into_inner())?);
+        if hasher.finalize() != expected {
+            return Err(Error::Checksum);
+        }
+
+        file.seek(SeekFrom::Start(header))?;
+        let mut reader = BufReader::new(&mut file);
+        let num = u32::from_le_bytes(read_array(&mut reader)?);
+        let top_hash = Option::binprot_read(&mut reader)?;
+        let empty = (0..=DEPTH)
+            .map(|_| v2::LedgerHash::binprot_read(&mut reader))
+            .collect::<Result<_, _>>()?;
+
+        let count = u64::from_le_bytes(read_array(&mut reader)?);
+        let mut hashes = BTreeMap::new();
+        for _ in 0..count {
+            let [depth] = read_array(&mut reader)?;
+            let pos = u64::from_le_bytes(read_array(&mut reader)?);
+            let hash = v2::LedgerHash::binprot_read(&mut reader)?;
+            hashes.insert((depth as u32, pos), hash);
+        }
+
+        let count = u64::from_le_bytes(read_array(&mut reader)?);
+        let mut table = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let index = u64::from_le_bytes(read_array(&mut reader)?);
+            let offset = u64::from_le_bytes(read_array(&mut reader)?);
+            let length = u32::from_le_bytes(read_array(&mut reader)?);
+            table.push((index, offset, length));
+        }
+        let data = reader.stream_position()?;
+        let accounts = table
+            .into_iter()
+            .map(|(index, offset, length)| (index, (data + offset, length)))
+            .collect();
+
+        Ok(Snapshot {
+            file,
+            num,
+            top_hash,
+            empty,
+            hashes,
+            accounts,
+        })
+    }
+
+    /// Read all the accounts into a ledger in memory.
+    pub fn to_ledger(&mut self) -> Result<SnarkedLedger, Error> {
+        let mut ledger = SnarkedLedger::empty();
+        ledger.num = self.num;
+        ledger.top_hash = self.top_hash.clone();
+        let indices = self.accounts.keys().copied().collect::<Vec<_>>();
+        for index in indices {
+            let account = self
+                .read_account(index)?
+                .expect("the index is in the table");
+            ledger
+                .inner
+                .set_at_index(AccountIndex(index), Box::new(account))
+                .map_err(|()| Error::Ledger(format!("cannot set the account {index}")))?;
+        }
+        Ok(ledger)
+    }
+
+    fn read_account(&mut self, index: u64) -> Result<Option<Account>, Error> {
+        let Some(&(offset, length)) = self.accounts.get(&index) else {
+            return Ok(None);
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; length as usize];
+        self.file.read_exact(&mut bytes)?;
+        Ok(Some(Account::binprot_read(&mut bytes.as_slice())?))
+    }
+}
+
+impl LedgerView for Snapshot {
+    fn num_accounts(&self) -> u32 {
+        self.num
+    }
+
+    fn hash_at(&mut self, depth: u32, pos: u64) -> Result<v2::LedgerHash, snarked_ledger::Error> {
+        let hash = match self.hashes.get(&(depth, pos)) {
+            Some(hash) => hash,
+            None => self
+                .empty
+                .get(depth as usize)
+                .ok_or_else(|| snarked_ledger::Error::Ledger(format!("depth {depth}")))?,
+        };
+        Ok(hash.clone())
+    }
+
+    fn account_at(&mut self, index: u64) -> Result<Option<Account>, snarked_ledger::Error> {
+        Ok(self.read_account(index)?)
+    }
+}
+
+/// Open the ledger file in either format, a snapshot is read lazily.
+pub fn open<P>(path: P) -> Result<Box<dyn LedgerView>, Error>
+where
+    P: AsRef<Path>,
+{
+    if is_snapshot(&path)? {
+        Ok(Box::new(Snapshot::open(path)?))
+    } else {
+        Ok(Box::new(SnarkedLedger::load_bin(File::open(path)?)?))
+    }
+}
+
+/// Load the ledger file in either format into memory.
+pub fn load<P>(path: P) -> Result<SnarkedLedger, Error>
+where
+    P: AsRef<Path>,
+{
+    if is_snapshot(&path)? {
+        Snapshot::open(path)?.to_ledger()
+    } else {
+        Ok(SnarkedLedger::load_bin(File::open(path)?)?)
+    }
+}
+
+/// Rewrite the file in the format of [`SnarkedLedger::store_bin`] as a snapshot.
+pub fn convert<P, Q>(from: P, to: Q) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut ledger = SnarkedLedger::load_bin(File::open(from)?)?;
+    store(&mut ledger, File::create(to)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_signer::CompressedPubKey;
+    use mina_tree::scan_state::currency::Balance;
+
+    use super::*;
+
+    fn ledger(num: u32) -> SnarkedLedger {
+        let public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+        let mut ledger = SnarkedLedger::empty();
+        for i in 0..num {
+            let mut account = Account::empty();
+            account.public_key = public_key.clone();
+            account.balance = Balance::of_nanomina_int_exn(1000 + i as u64);
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i as u64), Box::new(account))
+                .unwrap();
+        }
+        ledger.num = num;
+        ledger.top_hash = Some(ledger.hash_at(0, 0).unwrap());
+        ledger
+    }
+
+    #[test]
+    fn convert_and_serve() {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (bin, snap) = (dir.join("ledger.bin"), dir.join("ledger.snap"));
+
+        let mut ledger = ledger(21);
+        ledger.store_bin(File::create(&bin).unwrap()).unwrap();
+        convert(&bin, &snap).unwrap();
+        assert!(!is_snapshot(&bin).unwrap());
+        assert!(is_snapshot(&snap).unwrap());
+
+        let mut snapshot = Snapshot::open(&snap).unwrap();
+        assert_eq!(
+            snapshot.hash_at(0, 0).unwrap(),
+            ledger.hash_at(0, 0).unwrap()
+        );
+        assert_eq!(
+            snapshot.hash_at(32, 2).unwrap(),
+            ledger.hash_at(32, 2).unwrap()
+        );
+        // empty subtrees
+        assert_eq!(
+            snapshot.hash_at(32, 3).unwrap(),
+            ledger.hash_at(32, 3).unwrap()
+        );
+        assert_eq!(
+            snapshot.hash_at(1, 1).unwrap(),
+            ledger.hash_at(1, 1).unwrap()
+        );
+        for index in [0, 20, 21] {
+            assert_eq!(
+                snapshot.account_at(index).unwrap(),
+                ledger.account_at(index).unwrap()
+            );
+        }
+
+        let loaded = load(&snap).unwrap();
+        assert_eq!(loaded.num, 21);
+        assert_eq!(loaded.top_hash, ledger.top_hash);
+
+        let mut bytes = std::fs::read(&snap).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        std::fs::write(&snap, bytes).unwrap();
+        assert!(matches!(Snapshot::open(&snap), Err(Error::Checksum)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
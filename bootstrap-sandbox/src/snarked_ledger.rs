@@ -1,17 +1,310 @@
-use std::{io, future::Future, pin::Pin};
+use std::{
+    io::{self, Read, Write},
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+    future::Future,
+    pin::Pin,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::{Duration, Instant},
+};
 use binprot::{BinProtWrite, BinProtRead};
 use thiserror::Error;
 
-use mina_p2p_messages::{v2, rpc::AnswerSyncLedgerQueryV2, core::Info};
-use mina_tree::{Mask, Database, Account, BaseLedger, Address, AccountIndex};
+use mina_p2p_messages::{v2, rpc::AnswerSyncLedgerQueryV2, core::Info, rpc_kernel::RpcMethod};
+use libp2p::PeerId;
+use mina_curves::pasta::Fp;
+use mina_tree::{
+    Mask, Database, Account, AccountId, BaseLedger, Address, AccountIndex, TreeVersion, V2,
+};
 
-use super::client::Client;
+use super::{
+    client::{Client, ClientError},
+    snapshot,
+};
+
+/// Queries of the sync in flight at the same time.
+const WINDOW: usize = 64;
+/// The subtrees at this depth hold 8 accounts, the sync asks for their contents.
+const ACCOUNTS_DEPTH: u32 = 32;
+const LEDGER_DEPTH: u32 = 35;
+/// Wrong answers for one subtree before the sync gives up.
+const MAX_REFETCH: u8 = 3;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+type Response = <AnswerSyncLedgerQueryV2 as RpcMethod>::Response;
+
+/// The id of the query, and the answer with the peer that sent it.
+pub type Answer = (u64, Result<(PeerId, Response), ClientError>);
+
+/// Where the sync takes the answers from, the network or a test double.
+pub trait QuerySource {
+    /// Send the query to any peer but the `excluded`, the answer comes later
+    /// from [`QuerySource::next`].
+    fn send(
+        &mut self,
+        root: &v2::LedgerHash,
+        query: v2::MinaLedgerSyncLedgerQueryStableV1,
+        excluded: &BTreeSet<PeerId>,
+    ) -> u64;
+
+    /// Wait for the answer to any of these queries, it comes with the peer that sent it.
+    fn next<'a>(
+        &'a mut self,
+        pending: &'a BTreeSet<u64>,
+    ) -> Pin<Box<dyn Future<Output = Answer> + 'a>>;
+
+    /// The peer sent an answer that does not match the expected hash.
+    fn blame(&mut self, peer_id: PeerId);
+}
+
+impl QuerySource for Client {
+    fn send(
+        &mut self,
+        root: &v2::LedgerHash,
+        query: v2::MinaLedgerSyncLedgerQueryStableV1,
+        excluded: &BTreeSet<PeerId>,
+    ) -> u64 {
+        self.start_excluding::<AnswerSyncLedgerQueryV2>((root.0.clone(), query), excluded.clone())
+    }
+
+    fn next<'a>(
+        &'a mut self,
+        pending: &'a BTreeSet<u64>,
+    ) -> Pin<Box<dyn Future<Output = Answer> + 'a>> {
+        Box::pin(self.wait_any::<AnswerSyncLedgerQueryV2>(pending))
+    }
+
+    fn blame(&mut self, peer_id: PeerId) {
+        Client::blame(self, peer_id)
+    }
+}
+
+/// A subtree and its expected hash.
+struct Node {
+    depth: u32,
+    pos: u32,
+    hash: v2::LedgerHash,
+    /// Wrong answers so far.
+    refetch: u8,
+    /// The peers that could not answer, or answered wrong.
+    failed: BTreeSet<PeerId>,
+    /// Some of the accounts are copied from the base ledger.
+    copied: bool,
+}
+
+impl Node {
+    fn child(&self, bit: u32, hash: v2::LedgerHash) -> Self {
+        Node {
+            depth: self.depth + 1,
+            pos: self.pos * 2 + bit,
+            hash,
+            refetch: 0,
+            failed: BTreeSet::default(),
+            copied: false,
+        }
+    }
+
+    /// The children hashes must hash to the hash of the subtree.
+    fn check_children(&self, left: &v2::LedgerHash, right: &v2::LedgerHash) -> bool {
+        let height = (LEDGER_DEPTH - self.depth - 1) as usize;
+        V2::hash_node(height, left.to_field(), right.to_field()) == self.hash.to_field()
+    }
+
+    /// The accounts must hash to the hash of the subtree, the missing ones are empty.
+    fn check_contents(&self, accounts: &[Account]) -> bool {
+        if accounts.len() > 8 {
+            return false;
+        }
+        let empty = Account::empty().hash();
+        let mut hashes = (0..8)
+            .map(|o| accounts.get(o).map_or(empty, Account::hash))
+            .collect::<Vec<Fp>>();
+        for height in 0..(LEDGER_DEPTH - ACCOUNTS_DEPTH) as usize {
+            hashes = hashes
+                .chunks(2)
+                .map(|pair| V2::hash_node(height, pair[0], pair[1]))
+                .collect();
+        }
+        hashes[0] == self.hash.to_field()
+    }
+
+    /// The address is `depth` bits of `pos`, the most significant first, padded to bytes.
+    fn query(&self) -> v2::MinaLedgerSyncLedgerQueryStableV1 {
+        let b = ((self.depth as usize + 7) / 8).min(4);
+        let p = ((self.pos as u64) << (32 - self.depth)) as u32;
+        let addr = v2::MerkleAddressBinableArgStableV1(
+            (self.depth as i64).into(),
+            p.to_be_bytes()[..b].to_vec().into(),
+        );
+        if self.depth == ACCOUNTS_DEPTH {
+            v2::MinaLedgerSyncLedgerQueryStableV1::WhatContents(addr)
+        } else {
+            v2::MinaLedgerSyncLedgerQueryStableV1::WhatChildHashes(addr)
+        }
+    }
+}
+
+/// How much of the ledger the sync took from the base ledger, and how much from the peers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyncStats {
+    pub subtrees_reused: usize,
+    pub subtrees_fetched: usize,
+    pub accounts_reused: usize,
+    pub accounts_fetched: usize,
+}
+
+struct Progress {
+    stats: SyncStats,
+    last: Instant,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            stats: SyncStats::default(),
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Progress {
+    fn log(&mut self, num: u32, queued: usize, in_flight: usize) {
+        if self.last.elapsed() >= PROGRESS_INTERVAL {
+            self.last = Instant::now();
+            let s = &self.stats;
+            log::info!(
+                "sync: {}/{num} accounts, {} subtrees fetched, {} reused, {queued} queued, {in_flight} in flight",
+                s.accounts_fetched + s.accounts_reused,
+                s.subtrees_fetched,
+                s.subtrees_reused,
+            );
+        }
+    }
+}
 
 pub struct SnarkedLedger {
     pub inner: Mask,
     // NOTE: it is not the same as the merkle tree root
     pub top_hash: Option<v2::LedgerHash>,
     pub num: u32,
+    checkpoint: Option<Checkpoint>,
+}
+
+const ENTRY_ROOT: u8 = 0;
+const ENTRY_VERIFIED: u8 = 1;
+const ENTRY_CONTENTS: u8 = 2;
+
+/// Append-only log of the sync progress, a restarted sync continues from it.
+///
+/// The first entry is the root, then the verified subtrees and the received accounts.
+/// The restarted sync skips the verified subtrees without hashing them again.
+/// The subtrees copied from the base ledger are not logged, they are copied again,
+/// so a subtree with a copied part is not logged as verified either.
+/// An entry cut by a crash is dropped on load.
+struct Checkpoint {
+    path: PathBuf,
+    file: Option<File>,
+    verified: BTreeSet<(u32, u32)>,
+}
+
+impl Checkpoint {
+    fn new(path: PathBuf) -> Self {
+        Checkpoint {
+            path,
+            file: None,
+            verified: BTreeSet::new(),
+        }
+    }
+
+    /// Restore the progress of the sync of this root into the ledger,
+    /// or start a new log if there is none, or it is for another root.
+    fn open(&mut self, root: &v2::LedgerHash, ledger: &mut Mask) -> io::Result<()> {
+        self.verified.clear();
+        let mut accounts = 0;
+        if let Ok(bytes) = fs::read(&self.path) {
+            let mut reader = bytes.as_slice();
+            if matches!(Self::read_root(&mut reader), Ok(ref r) if r == root) {
+                let mut valid = bytes.len() - reader.len();
+                while self.read_entry(&mut reader, ledger, &mut accounts).is_ok() {
+                    valid = bytes.len() - reader.len();
+                }
+                let file = OpenOptions::new().write(true).open(&self.path)?;
+                file.set_len(valid as u64)?;
+                self.file = Some(OpenOptions::new().append(true).open(&self.path)?);
+                log::info!(
+                    "resume sync: {accounts} accounts, {} subtrees verified",
+                    self.verified.len()
+                );
+                return Ok(());
+            }
+        }
+
+        let mut file = File::create(&self.path)?;
+        let mut entry = vec![ENTRY_ROOT];
+        root.binprot_write(&mut entry)?;
+        file.write_all(&entry)?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn read_root(reader: &mut &[u8]) -> Result<v2::LedgerHash, binprot::Error> {
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        if tag[0] != ENTRY_ROOT {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        v2::LedgerHash::binprot_read(reader)
+    }
+
+    fn read_entry(
+        &mut self,
+        reader: &mut &[u8],
+        ledger: &mut Mask,
+        accounts: &mut usize,
+    ) -> Result<(), binprot::Error> {
+        let mut head = [0; 9];
+        reader.read_exact(&mut head)?;
+        let depth = u32::from_le_bytes(head[1..5].try_into().expect("4 bytes"));
+        let pos = u32::from_le_bytes(head[5..9].try_into().expect("4 bytes"));
+        match head[0] {
+            ENTRY_VERIFIED => {
+                self.verified.insert((depth, pos));
+            }
+            ENTRY_CONTENTS => {
+                let list = Vec::<Account>::binprot_read(reader)?;
+                *accounts += list.len();
+                for (o, account) in list.into_iter().enumerate() {
+                    let index = AccountIndex((pos * 8) as u64 + o as u64);
+                    ledger
+                        .set_at_index(index, Box::new(account))
+                        .map_err(|()| io::Error::from(io::ErrorKind::InvalidData))?;
+                }
+            }
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidData).into()),
+        }
+        Ok(())
+    }
+
+    /// Each entry goes in one write, so a crash can cut only the last one.
+    fn append(&mut self, tag: u8, depth: u32, pos: u32, accounts: &[Account]) -> io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let mut entry = vec![tag];
+        entry.extend_from_slice(&depth.to_le_bytes());
+        entry.extend_from_slice(&pos.to_le_bytes());
+        if tag == ENTRY_CONTENTS {
+            accounts.binprot_write(&mut entry)?;
+        }
+        file.write_all(&entry)
+    }
+
+    /// The sync is complete, the log is not needed.
+    fn finish(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.verified.clear();
+        fs::remove_file(&self.path)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -20,6 +313,25 @@ pub enum Error {
     Serde(#[from] serde_json::Error),
     #[error("{0}")]
     Io(#[from] io::Error),
+    #[error("{0}")]
+    Client(#[from] ClientError),
+    #[error("unexpected answer to the query of the number of accounts")]
+    UnexpectedNumAccounts,
+    #[error("no peer sent a valid answer for the subtree {pos} at depth {depth}")]
+    NoValidAnswer { depth: u32, pos: u32 },
+    #[error("cannot set the account {0}")]
+    SetAccount(u64),
+    #[error("ledger: {0}")]
+    Ledger(String),
+    #[error("{0}")]
+    Snapshot(#[from] snapshot::Error),
+    #[error("the subtree {pos} at depth {depth} has hash {actual:?}, expected {expected:?}")]
+    HashMismatch {
+        depth: u32,
+        pos: u32,
+        expected: v2::LedgerHash,
+        actual: v2::LedgerHash,
+    },
 }
 
 impl SnarkedLedger {
@@ -28,9 +340,20 @@ impl SnarkedLedger {
             inner: Mask::new_root(Database::create(35)),
             top_hash: None,
             num: 0,
+            checkpoint: None,
         }
     }
 
+    /// Log the progress of [`SnarkedLedger::sync_new`] to this file and continue from it
+    /// if the previous sync of the same root did not finish.
+    pub fn with_checkpoint<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.checkpoint = Some(Checkpoint::new(path.as_ref().to_owned()));
+        self
+    }
+
     // for debugging
     pub fn store_bin<W>(&self, mut writer: W) -> io::Result<()>
     where
@@ -64,185 +387,858 @@ impl SnarkedLedger {
             inner,
             top_hash,
             num,
+            checkpoint: None,
         })
     }
 
-    pub async fn sync_new(&mut self, client: &mut Client, root: &v2::LedgerHash) {
-        let q = v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts;
-        let r = client
-            .rpc::<AnswerSyncLedgerQueryV2>((root.0.clone(), q))
-            .await
-            .unwrap()
-            .0
-            .unwrap();
-        let (num, hash) = match r {
-            v2::MinaLedgerSyncLedgerAnswerStableV2::NumAccounts(num, hash) => (num.0, hash),
-            _ => panic!(),
+    /// Sync the ledger with this root, reusing the accounts the ledger already has.
+    pub async fn sync_new<S>(
+        &mut self,
+        source: &mut S,
+        root: &v2::LedgerHash,
+    ) -> Result<SyncStats, Error>
+    where
+        S: QuerySource,
+    {
+        let mut base = SnarkedLedger {
+            inner: self.inner.clone(),
+            top_hash: None,
+            num: self.num,
+            checkpoint: None,
         };
-        self.top_hash = Some(hash.clone());
-        self.num = num as _;
+        self.sync_diff(source, root, &mut base).await
+    }
 
-        if self.inner.num_accounts() > num as _ {
-            self.inner = Mask::new_root(Database::create(35));
+    /// Sync the ledger with this root, starting from any local ledger, for example the
+    /// previous epoch ledger, in memory or on disk. Only the subtrees whose hashes differ
+    /// from `base` are fetched, the rest is copied.
+    pub async fn sync_diff<S, B>(
+        &mut self,
+        source: &mut S,
+        root: &v2::LedgerHash,
+        base: &mut B,
+    ) -> Result<SyncStats, Error>
+    where
+        S: QuerySource,
+        B: LedgerView + ?Sized,
+    {
+        let (num, hash) = num_accounts(source, root).await?;
+        self.top_hash = Some(hash);
+        self.num = num;
+
+        self.inner = Mask::new_root(Database::create(LEDGER_DEPTH as _));
+        // taken out while the sync writes to the ledger
+        let mut checkpoint = self.checkpoint.take();
+        if let Some(checkpoint) = &mut checkpoint {
+            checkpoint.open(root, &mut self.inner)?;
         }
+        let result = sync_tree(source, root, self, base, checkpoint.as_mut()).await;
+        self.checkpoint = checkpoint;
+        let stats = result?;
 
-        self.sync_at_depth_new(client, root.clone(), hash.clone(), 0, 0)
-            .await;
-        let actual_hash = self.inner.merkle_root();
-        let actual_hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual_hash.into()));
-        assert_eq!(actual_hash, root.clone());
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.finish()?;
+        }
+        Ok(stats)
     }
+}
 
-    fn sync_at_depth_boxed_new<'a, 'b: 'a>(
-        &'b mut self,
-        client: &'a mut Client,
-        root: v2::LedgerHash,
-        hash: v2::LedgerHash,
-        depth: i32,
-        pos: u32,
-    ) -> Pin<Box<dyn Future<Output = ()> + 'a>> {
-        Box::pin(self.sync_at_depth_new(client, root, hash, depth, pos))
+/// The number of accounts of the ledger with this root, and the hash that goes with it.
+pub async fn num_accounts<S>(
+    source: &mut S,
+    root: &v2::LedgerHash,
+) -> Result<(u32, v2::LedgerHash), Error>
+where
+    S: QuerySource,
+{
+    let q = v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts;
+    let id = source.send(root, q, &BTreeSet::default());
+    let (_, r) = source.next(&[id].into()).await;
+    match r?.1 .0 {
+        Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::NumAccounts(num, hash)) => {
+            Ok((num.0 as _, hash))
+        }
+        _ => Err(Error::UnexpectedNumAccounts),
     }
+}
 
-    async fn sync_at_depth_new(
-        &mut self,
-        client: &mut Client,
-        root: v2::LedgerHash,
-        hash: v2::LedgerHash,
-        depth: i32,
-        pos: u32,
-    ) {
-        let addr = Address::from_index(AccountIndex(pos as _), depth as _);
-        let actual_hash = self.inner.get_inner_hash_at_addr(addr.clone()).unwrap();
-        if depth == 0 && root.0 == actual_hash.into() || depth > 0 && hash.0 == actual_hash.into() {
-            return;
+/// A ledger the sync writes to, in memory or on disk.
+pub trait LedgerTarget: LedgerView {
+    fn set_account(&mut self, index: u64, account: Account) -> Result<(), Error>;
+
+    /// All the children of the subtree are written, update its hash if the ledger keeps
+    /// the hashes, a ledger in memory computes them when asked.
+    fn update_hash(&mut self, depth: u32, pos: u64) -> Result<(), Error> {
+        let _ = (depth, pos);
+        Ok(())
+    }
+
+    /// The subtree of `base` has the expected hash, copy it.
+    /// Returns the number of the copied accounts.
+    fn copy_subtree<B>(&mut self, base: &mut B, depth: u32, pos: u64) -> Result<usize, Error>
+    where
+        B: LedgerView + ?Sized,
+    {
+        let height = LEDGER_DEPTH - depth;
+        let start = pos << height;
+        let end = (start + (1 << height)).min(base.num_accounts() as u64);
+        for index in start..end {
+            if let Some(account) = base.account_at(index)? {
+                self.set_account(index, account)?;
+            }
         }
+        Ok(end.saturating_sub(start) as usize)
+    }
+}
 
-        if depth == 32 {
-            let p = pos.to_be_bytes().to_vec();
-            let q = v2::MinaLedgerSyncLedgerQueryStableV1::WhatContents(
-                v2::MerkleAddressBinableArgStableV1((depth as i64).into(), p.into()),
-            );
-            log::info!("{}", serde_json::to_string(&q).unwrap());
-            let r = client
-                .rpc::<AnswerSyncLedgerQueryV2>((root.0.clone(), q))
-                .await
-                .unwrap()
-                .0;
-            match r {
-                Err(Info::CouldNotConstruct(s)) => {
-                    log::error!(
-                        "num: {}, could not construct {}",
-                        self.num,
-                        s.to_string_lossy()
-                    );
-                }
-                Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(accounts)) => {
-                    for (o, account) in accounts.into_iter().enumerate() {
-                        let account = Account::from(&account);
-                        self.inner
-                            .set_at_index(
-                                AccountIndex((pos * 8) as u64 + o as u64),
-                                Box::new(account),
-                            )
-                            .unwrap();
-                    }
+impl LedgerTarget for SnarkedLedger {
+    fn set_account(&mut self, index: u64, account: Account) -> Result<(), Error> {
+        self.inner
+            .set_at_index(AccountIndex(index), Box::new(account))
+            .map_err(|()| Error::SetAccount(index))
+    }
+}
+
+/// Sync the ledger with this root into the `target`, it has the number of accounts already
+/// and keeps the subtrees written by a sync that did not finish. Only the subtrees whose
+/// hashes differ from `base` are fetched, the rest is copied.
+pub async fn sync_into<S, T, B>(
+    source: &mut S,
+    root: &v2::LedgerHash,
+    target: &mut T,
+    base: &mut B,
+) -> Result<SyncStats, Error>
+where
+    S: QuerySource,
+    T: LedgerTarget,
+    B: LedgerView + ?Sized,
+{
+    sync_tree(source, root, target, base, None).await
+}
+
+/// Breadth-first, keeps up to [`WINDOW`] queries in flight. Every answer is checked
+/// against the expected hash, the peer of a wrong answer is blamed and the query
+/// goes again to another peer.
+async fn sync_tree<S, T, B>(
+    source: &mut S,
+    root: &v2::LedgerHash,
+    target: &mut T,
+    base: &mut B,
+    mut checkpoint: Option<&mut Checkpoint>,
+) -> Result<SyncStats, Error>
+where
+    S: QuerySource,
+    T: LedgerTarget,
+    B: LedgerView + ?Sized,
+{
+    let mut queue = VecDeque::from([Node {
+        depth: 0,
+        pos: 0,
+        hash: root.clone(),
+        refetch: 0,
+        failed: BTreeSet::default(),
+        copied: false,
+    }]);
+    let mut in_flight = BTreeMap::<u64, Node>::new();
+    // the subtrees waiting for their children, and the number of children left
+    let mut waiting = BTreeMap::<(u32, u32), (Node, u8)>::new();
+    let mut progress = Progress::default();
+
+    while !queue.is_empty() || !in_flight.is_empty() {
+        while in_flight.len() < WINDOW {
+            let Some(node) = queue.pop_front() else {
+                break;
+            };
+            let verified = checkpoint
+                .as_ref()
+                .is_some_and(|c| c.verified.contains(&(node.depth, node.pos)));
+            if verified {
+                done(target, &mut checkpoint, &mut waiting, node, true)?;
+                continue;
+            }
+            // empty, or restored from the checkpoint, or written by the previous sync
+            if target.hash_at(node.depth, node.pos as u64)? == node.hash {
+                done(target, &mut checkpoint, &mut waiting, node, false)?;
+                continue;
+            }
+            if base.hash_at(node.depth, node.pos as u64)? == node.hash {
+                let mut node = node;
+                progress.stats.subtrees_reused += 1;
+                progress.stats.accounts_reused +=
+                    target.copy_subtree(base, node.depth, node.pos as u64)?;
+                node.copied = true;
+                done(target, &mut checkpoint, &mut waiting, node, false)?;
+                continue;
+            }
+            let id = source.send(root, node.query(), &node.failed);
+            in_flight.insert(id, node);
+        }
+        if in_flight.is_empty() {
+            continue;
+        }
+
+        let pending = in_flight.keys().copied().collect();
+        let (id, answer) = source.next(&pending).await;
+        let mut node = in_flight.remove(&id).expect("must be in flight");
+        let (peer_id, answer) = match answer {
+            // every peer that could answer failed this subtree
+            Err(ClientError::NoPeer(_)) => {
+                return Err(Error::NoValidAnswer {
+                    depth: node.depth,
+                    pos: node.pos,
+                })
+            }
+            answer => answer?,
+        };
+        let retry = match answer.0 {
+            Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(l, r))
+                if node.depth < ACCOUNTS_DEPTH && node.check_children(&l, &r) =>
+            {
+                progress.stats.subtrees_fetched += 1;
+                queue.push_back(node.child(0, l));
+                queue.push_back(node.child(1, r));
+                waiting.insert((node.depth, node.pos), (node, 2));
+                None
+            }
+            Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(accounts))
+                if node.depth == ACCOUNTS_DEPTH =>
+            {
+                let accounts = accounts.iter().map(Account::from).collect::<Vec<_>>();
+                if node.check_contents(&accounts) {
+                    progress.stats.subtrees_fetched += 1;
+                    progress.stats.accounts_fetched += accounts.len();
+                    set_contents(target, &mut checkpoint, &node, accounts)?;
+                    done(target, &mut checkpoint, &mut waiting, node, false)?;
+                    None
+                } else {
+                    log::warn!("{peer_id} sent wrong contents of the subtree {}", node.pos);
+                    source.blame(peer_id);
+                    node.refetch += 1;
+                    node.failed.insert(peer_id);
+                    Some(node)
                 }
-                _ => panic!(),
             }
-        } else {
-            let b = ((depth as usize + 7) / 8).min(4);
-            let p = pos * (1 << (32 - depth));
-            let p = p.to_be_bytes()[..b].to_vec();
-            let q = v2::MinaLedgerSyncLedgerQueryStableV1::WhatChildHashes(
-                v2::MerkleAddressBinableArgStableV1((depth as i64).into(), p.into()),
-            );
-            log::info!("{}", serde_json::to_string(&q).unwrap());
-            let r = client
-                .rpc::<AnswerSyncLedgerQueryV2>((root.0.clone(), q))
-                .await
-                .unwrap()
-                .0
-                .unwrap();
-            match r {
-                v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(l, r) => {
-                    self.sync_at_depth_boxed_new(client, root.clone(), l, depth + 1, pos * 2)
-                        .await;
-                    self.sync_at_depth_boxed_new(client, root.clone(), r, depth + 1, pos * 2 + 1)
-                        .await;
+            Err(Info::CouldNotConstruct(s)) => {
+                log::warn!(
+                    "num: {}, {peer_id} could not construct {}",
+                    target.num_accounts(),
+                    s.to_string_lossy()
+                );
+                node.refetch += 1;
+                node.failed.insert(peer_id);
+                Some(node)
+            }
+            _ => {
+                log::warn!(
+                    "{peer_id} sent a wrong answer for the subtree {} at depth {}",
+                    node.pos,
+                    node.depth,
+                );
+                source.blame(peer_id);
+                node.refetch += 1;
+                node.failed.insert(peer_id);
+                Some(node)
+            }
+        };
+        // the answer is wrong, ask again, but not the peers that failed
+        if let Some(node) = retry {
+            if node.refetch >= MAX_REFETCH {
+                return Err(Error::NoValidAnswer {
+                    depth: node.depth,
+                    pos: node.pos,
+                });
+            }
+            queue.push_front(node);
+        }
+        progress.log(target.num_accounts(), queue.len(), in_flight.len());
+    }
+    let stats = progress.stats;
+    log::info!(
+        "synced {} accounts, {} subtrees fetched, {} reused, {} accounts fetched, {} reused",
+        target.num_accounts(),
+        stats.subtrees_fetched,
+        stats.subtrees_reused,
+        stats.accounts_fetched,
+        stats.accounts_reused,
+    );
+    Ok(stats)
+}
+
+fn set_contents<T>(
+    target: &mut T,
+    checkpoint: &mut Option<&mut Checkpoint>,
+    node: &Node,
+    accounts: Vec<Account>,
+) -> Result<(), Error>
+where
+    T: LedgerTarget,
+{
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.append(ENTRY_CONTENTS, node.depth, node.pos, &accounts)?;
+    }
+    for (o, account) in accounts.into_iter().enumerate() {
+        target.set_account((node.pos * 8) as u64 + o as u64, account)?;
+    }
+    for depth in (ACCOUNTS_DEPTH..LEDGER_DEPTH).rev() {
+        let first = (node.pos as u64) << (depth - ACCOUNTS_DEPTH);
+        for pos in first..(first + (1 << (depth - ACCOUNTS_DEPTH))) {
+            target.update_hash(depth, pos)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the subtree and its parents that have all the children done, and log them
+/// as verified. The subtree is written, its hash is up to date, the hash of a parent is
+/// updated when all its children are done. The subtree `verified` by the checkpoint is
+/// not checked again.
+fn done<T>(
+    target: &mut T,
+    checkpoint: &mut Option<&mut Checkpoint>,
+    waiting: &mut BTreeMap<(u32, u32), (Node, u8)>,
+    mut node: Node,
+    mut verified: bool,
+) -> Result<(), Error>
+where
+    T: LedgerTarget,
+{
+    loop {
+        if !verified {
+            let actual = target.hash_at(node.depth, node.pos as u64)?;
+            if actual != node.hash {
+                return Err(Error::HashMismatch {
+                    depth: node.depth,
+                    pos: node.pos,
+                    expected: node.hash,
+                    actual,
+                });
+            }
+            if let (Some(checkpoint), false) = (checkpoint.as_mut(), node.copied) {
+                checkpoint.append(ENTRY_VERIFIED, node.depth, node.pos, &[])?;
+            }
+        }
+        verified = false;
+        if node.depth == 0 {
+            break;
+        }
+        let parent = (node.depth - 1, node.pos / 2);
+        let Some((parent_node, left)) = waiting.get_mut(&parent) else {
+            break;
+        };
+        parent_node.copied |= node.copied;
+        *left -= 1;
+        if *left > 0 {
+            break;
+        }
+        node = waiting.remove(&parent).expect("just found").0;
+        target.update_hash(node.depth, node.pos as u64)?;
+    }
+    Ok(())
+}
+
+/// What the ledger sync server needs from a ledger, either loaded in memory or read
+/// from a snapshot file on demand.
+pub trait LedgerView {
+    fn num_accounts(&self) -> u32;
+
+    /// The hash of the subtree `pos` at `depth`, the root is at depth 0, the accounts at 35.
+    fn hash_at(&mut self, depth: u32, pos: u64) -> Result<v2::LedgerHash, Error>;
+
+    fn account_at(&mut self, index: u64) -> Result<Option<Account>, Error>;
+
+    /// The index of the account, scans the ledger unless the ledger has an index.
+    fn index_of(&mut self, id: &AccountId) -> Result<Option<u64>, Error> {
+        for index in 0..(self.num_accounts() as u64) {
+            if let Some(account) = self.account_at(index)? {
+                if account.id() == *id {
+                    return Ok(Some(index));
                 }
-                _ => panic!(),
-            };
+            }
         }
+        Ok(None)
+    }
 
-        let addr = Address::from_index(AccountIndex(pos as _), depth as _);
-        let actual_hash = self.inner.get_inner_hash_at_addr(addr).unwrap();
-        let actual_hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual_hash.into()));
-        if depth == 0 {
-            assert_eq!(root, actual_hash);
-        } else {
-            assert_eq!(hash, actual_hash);
+    fn account_of(&mut self, id: &AccountId) -> Result<Option<Account>, Error> {
+        match self.index_of(id)? {
+            Some(index) => self.account_at(index),
+            None => Ok(None),
         }
     }
 
-    pub fn serve_query(
+    /// The hash that goes with the number of accounts, as Mina defines it: the hash of
+    /// the leftmost subtree that is just big enough for all the accounts.
+    fn content_hash(&mut self) -> Result<v2::LedgerHash, Error> {
+        let height = ceil_log2(self.num_accounts() as u64);
+        self.hash_at(LEDGER_DEPTH - height, 0)
+    }
+
+    fn serve_query(
         &mut self,
         q: v2::MinaLedgerSyncLedgerQueryStableV1,
-    ) -> v2::MinaLedgerSyncLedgerAnswerStableV2 {
+    ) -> Result<v2::MinaLedgerSyncLedgerAnswerStableV2, Info> {
         log::info!("query: {q:?}");
+        let could_not_construct = |msg: String| Info::CouldNotConstruct(msg.as_str().into());
         match q {
             v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts => {
-                v2::MinaLedgerSyncLedgerAnswerStableV2::NumAccounts(
-                    (self.num as i64).into(),
-                    self.top_hash.as_ref().unwrap().clone(),
-                )
+                let hash = self
+                    .content_hash()
+                    .map_err(|err| could_not_construct(err.to_string()))?;
+                Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::NumAccounts(
+                    (self.num_accounts() as i64).into(),
+                    hash,
+                ))
             }
             v2::MinaLedgerSyncLedgerQueryStableV1::WhatChildHashes(address) => {
-                let addr = Address::from(address);
-
-                let hash = self
-                    .inner
-                    .get_inner_hash_at_addr(addr.child_left())
-                    .unwrap();
-                let left = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash.into()));
+                let (depth, pos) = self.validate(&address, LEDGER_DEPTH - 1)?;
 
-                let hash = self
-                    .inner
-                    .get_inner_hash_at_addr(addr.child_right())
-                    .unwrap();
-                let right = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash.into()));
+                let mut child = |pos| {
+                    self.hash_at(depth + 1, pos)
+                        .map_err(|err| could_not_construct(err.to_string()))
+                };
+                let left = child(pos * 2)?;
+                let right = child(pos * 2 + 1)?;
 
-                v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(left, right)
+                Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(
+                    left, right,
+                ))
             }
             v2::MinaLedgerSyncLedgerQueryStableV1::WhatContents(address) => {
-                let addr = Address::from(address);
-
-                let depth = addr.length();
-                let pos = addr.to_index().0;
+                let (depth, pos) = self.validate(&address, LEDGER_DEPTH)?;
+                if depth < ACCOUNTS_DEPTH {
+                    let msg = format!("contents at depth {depth}, the subtree is too big");
+                    return Err(could_not_construct(msg));
+                }
 
+                let batch_length = 1u64 << (LEDGER_DEPTH - depth);
+                let start = pos * batch_length;
+                let end = (start + batch_length).min(self.num_accounts() as u64);
                 let mut accounts = Vec::with_capacity(8);
-                let mut offset = 0;
-                let batch_length = 1u64 << (35 - depth);
-                loop {
-                    if offset == batch_length {
-                        break;
+                for index in start..end {
+                    match self.account_at(index) {
+                        Ok(Some(account)) => accounts.push((&account).into()),
+                        Ok(None) => break,
+                        Err(err) => return Err(could_not_construct(err.to_string())),
                     }
+                }
+                Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(
+                    accounts,
+                ))
+            }
+        }
+    }
 
-                    let pos = pos * batch_length + offset;
-                    offset += 1;
-                    if pos == self.num as u64 {
-                        break;
+    /// The depth and the position of the address, if it is well-formed, not deeper than
+    /// `max_depth` and has accounts in it.
+    fn validate(
+        &self,
+        address: &v2::MerkleAddressBinableArgStableV1,
+        max_depth: u32,
+    ) -> Result<(u32, u64), Info> {
+        let depth = address.0 .0;
+        let bytes = address.1.as_ref();
+        if depth < 0 || depth > max_depth as i64 {
+            let msg = format!("invalid address, depth {depth}, at most {max_depth}");
+            return Err(Info::CouldNotConstruct(msg.as_str().into()));
+        }
+        let depth = depth as u32;
+        if bytes.len() != (depth as usize + 7) / 8 {
+            let msg = format!("invalid address, {} bytes at depth {depth}", bytes.len());
+            return Err(Info::CouldNotConstruct(msg.as_str().into()));
+        }
+        let pos = Address::from(address.clone()).to_index().0;
+        let start = pos << (LEDGER_DEPTH - depth);
+        if depth > 0 && start >= self.num_accounts() as u64 {
+            let msg = format!("address {pos} at depth {depth} is out of range");
+            return Err(Info::CouldNotConstruct(msg.as_str().into()));
+        }
+        Ok((depth, pos))
+    }
+}
+
+fn ceil_log2(n: u64) -> u32 {
+    if n <= 1 {
+        0
+    } else {
+        u64::BITS - (n - 1).leading_zeros()
+    }
+}
+
+impl LedgerView for SnarkedLedger {
+    fn num_accounts(&self) -> u32 {
+        self.num
+    }
+
+    fn hash_at(&mut self, depth: u32, pos: u64) -> Result<v2::LedgerHash, Error> {
+        let addr = Address::from_index(AccountIndex(pos), depth as _);
+        let hash = self
+            .inner
+            .get_inner_hash_at_addr(addr)
+            .map_err(Error::Ledger)?;
+        Ok(v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(
+            hash.into(),
+        )))
+    }
+
+    fn account_at(&mut self, index: u64) -> Result<Option<Account>, Error> {
+        let addr = Address::from_index(AccountIndex(index), LEDGER_DEPTH as _);
+        Ok(self.inner.get(addr).map(|account| *account))
+    }
+
+    fn index_of(&mut self, id: &AccountId) -> Result<Option<u64>, Error> {
+        Ok(self
+            .inner
+            .location_of_account(id)
+            .map(|addr| addr.to_index().0))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use mina_p2p_messages::rpc_kernel::RpcResult;
+    use mina_signer::CompressedPubKey;
+    use mina_tree::scan_state::currency::Balance;
+
+    use super::{super::ledger_files, *};
+
+    /// Answers from the ledger, and hangs forever after `budget` answers.
+    /// The liars answer first and lie until they are blamed.
+    /// The stubborn peer cannot construct any answer, it is asked unless excluded.
+    pub(crate) struct Serve {
+        ledger: SnarkedLedger,
+        budget: usize,
+        answers: BTreeMap<u64, (Option<PeerId>, Response)>,
+        last: u64,
+        liars: Vec<PeerId>,
+        blamed: BTreeSet<PeerId>,
+        stubborn: Option<PeerId>,
+    }
+
+    impl QuerySource for Serve {
+        fn send(
+            &mut self,
+            _: &v2::LedgerHash,
+            query: v2::MinaLedgerSyncLedgerQueryStableV1,
+            excluded: &BTreeSet<PeerId>,
+        ) -> u64 {
+            self.last += 1;
+            // the number of accounts is asked once, without exclusions
+            let stubborn = self.stubborn.filter(|peer_id| {
+                !excluded.contains(peer_id)
+                    && !matches!(query, v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts)
+            });
+            let answer = match stubborn {
+                Some(_) => Err(Info::CouldNotConstruct("no such ledger".into())),
+                None => self.ledger.serve_query(query),
+            };
+            self.answers
+                .insert(self.last, (stubborn, RpcResult(answer)));
+            self.last
+        }
+
+        fn next<'a>(
+            &'a mut self,
+            pending: &'a BTreeSet<u64>,
+        ) -> Pin<Box<dyn Future<Output = Answer> + 'a>> {
+            Box::pin(async move {
+                if self.budget == 0 {
+                    return std::future::pending().await;
+                }
+                self.budget -= 1;
+                let id = *pending.first().expect("must not be empty");
+                let (stubborn, mut answer) = self.answers.remove(&id).expect("must be sent");
+                if let Some(peer_id) = stubborn {
+                    return (id, Ok((peer_id, answer)));
+                }
+                let liar = self.liars.iter().find(|p| !self.blamed.contains(p));
+                let peer_id = match (liar, &mut answer.0) {
+                    (
+                        Some(liar),
+                        Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(l, r)),
+                    ) => {
+                        std::mem::swap(l, r);
+                        *liar
                     }
-                    let addr = Address::from_index(AccountIndex(pos as _), 35);
-                    let account = self.inner.get(addr);
-                    if let Some(account) = account {
-                        accounts.push((&*account).into());
-                    } else {
-                        break;
+                    (
+                        Some(liar),
+                        Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(accounts)),
+                    ) => {
+                        accounts.remove(0);
+                        *liar
                     }
-                }
-                v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(accounts)
+                    _ => PeerId::random(),
+                };
+                (id, Ok((peer_id, answer)))
+            })
+        }
+
+        fn blame(&mut self, peer_id: PeerId) {
+            self.blamed.insert(peer_id);
+        }
+    }
+
+    /// The accounts in the list of `changed` have another balance.
+    pub(crate) fn ledger(num: u32, changed: &[u32]) -> SnarkedLedger {
+        let public_key = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+        let mut ledger = SnarkedLedger::empty();
+        for i in 0..num {
+            let mut account = Account::empty();
+            account.public_key = public_key.clone();
+            let extra = if changed.contains(&i) { 1 } else { 0 };
+            account.balance = Balance::of_nanomina_int_exn(1000 + i as u64 + extra);
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i as u64), Box::new(account))
+                .unwrap();
+        }
+        ledger.num = num;
+        ledger
+    }
+
+    impl Serve {
+        fn new(ledger: SnarkedLedger, budget: usize, liars: usize) -> Self {
+            Serve {
+                ledger,
+                budget,
+                answers: BTreeMap::new(),
+                last: 0,
+                liars: (0..liars).map(|_| PeerId::random()).collect(),
+                blamed: BTreeSet::new(),
+                stubborn: None,
+            }
+        }
+    }
+
+    pub(crate) fn source(num: u32, budget: usize, liars: usize) -> (Serve, v2::LedgerHash) {
+        let mut ledger = ledger(num, &[]);
+        let root = ledger.inner.merkle_root();
+        let root = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(root.into()));
+        ledger.top_hash = Some(root.clone());
+        (Serve::new(ledger, budget, liars), root)
+    }
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Poll once, the source never waits, so the sync either completes or hangs.
+    pub(crate) fn run<F>(f: F) -> Option<F::Output>
+    where
+        F: Future,
+    {
+        let waker = Waker::from(Arc::new(Noop));
+        match pin!(f).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => Some(output),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn resume_after_kill() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ledger.checkpoint");
+
+        let (mut full, root) = source(100, usize::MAX, 0);
+        run(SnarkedLedger::empty().sync_new(&mut full, &root))
+            .unwrap()
+            .unwrap();
+        let total = full.last as usize;
+
+        // killed at pseudo-random points, each time the restarted sync continues
+        let mut seed = 0x2545f491u32;
+        let mut kill_points = vec![total - 1];
+        for _ in 0..8 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            kill_points.push(seed as usize % total);
+        }
+        for kill_at in kill_points {
+            let (mut killed, _) = source(100, kill_at, 0);
+            let mut ledger = SnarkedLedger::empty().with_checkpoint(&path);
+            assert!(run(ledger.sync_new(&mut killed, &root)).is_none());
+            drop(ledger);
+
+            let (mut resumed, _) = source(100, usize::MAX, 0);
+            let mut ledger = SnarkedLedger::empty().with_checkpoint(&path);
+            run(ledger.sync_new(&mut resumed, &root)).unwrap().unwrap();
+            assert!(resumed.last as usize <= total);
+            if kill_at == total - 1 {
+                // only the last subtree is left
+                assert!((resumed.last as usize) < total);
+            }
+            let actual = ledger.inner.merkle_root();
+            let actual = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual.into()));
+            assert_eq!(actual, root);
+            assert!(!path.exists());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_verified() {
+        let dir = std::env::temp_dir().join(format!("verified-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ledger.checkpoint");
+
+        let (mut full, root) = source(100, usize::MAX, 0);
+        run(SnarkedLedger::empty().sync_new(&mut full, &root))
+            .unwrap()
+            .unwrap();
+
+        // killed before the last answer, everything else is verified
+        let (mut killed, _) = source(100, full.last as usize - 1, 0);
+        let mut ledger = SnarkedLedger::empty().with_checkpoint(&path);
+        assert!(run(ledger.sync_new(&mut killed, &root)).is_none());
+        drop(ledger);
+
+        let mut checkpoint = Checkpoint::new(path.clone());
+        let mut mask = Mask::new_root(Database::create(LEDGER_DEPTH as _));
+        checkpoint.open(&root, &mut mask).unwrap();
+        assert!(checkpoint.verified.contains(&(ACCOUNTS_DEPTH, 0)));
+        assert!(checkpoint.verified.contains(&(ACCOUNTS_DEPTH - 1, 0)));
+        assert!(!checkpoint.verified.contains(&(0, 0)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn blame_liars() {
+        let (mut source, root) = source(100, usize::MAX, MAX_REFETCH as usize - 1);
+        let mut ledger = SnarkedLedger::empty();
+        run(ledger.sync_new(&mut source, &root)).unwrap().unwrap();
+        assert_eq!(source.blamed.len(), source.liars.len());
+        let actual = ledger.inner.merkle_root();
+        let actual = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual.into()));
+        assert_eq!(actual, root);
+    }
+
+    #[test]
+    fn exclude_failed_peer() {
+        let (mut source, root) = source(100, usize::MAX, 0);
+        source.stubborn = Some(PeerId::random());
+        let mut ledger = SnarkedLedger::empty();
+        run(ledger.sync_new(&mut source, &root)).unwrap().unwrap();
+        let actual = ledger.inner.merkle_root();
+        let actual = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual.into()));
+        assert_eq!(actual, root);
+    }
+
+    #[test]
+    fn no_valid_answer() {
+        let (mut source, root) = source(100, usize::MAX, MAX_REFETCH as usize);
+        let mut ledger = SnarkedLedger::empty();
+        let result = run(ledger.sync_new(&mut source, &root)).unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::NoValidAnswer { depth: 0, pos: 0 })
+        ));
+    }
+
+    #[test]
+    fn diff() {
+        let (mut source, root) = source(100, usize::MAX, 0);
+        // two accounts changed, and the base has more accounts
+        let mut previous = ledger(120, &[3, 60]);
+        let stats = run(previous.sync_new(&mut source, &root)).unwrap().unwrap();
+        let actual = previous.inner.merkle_root();
+        let actual = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual.into()));
+        assert_eq!(actual, root);
+
+        // the leaves of the changed accounts, and the one cut by the end of the ledger
+        assert_eq!(stats.accounts_fetched, 8 + 8 + 4);
+        assert!(stats.subtrees_reused > 0);
+        assert_eq!(stats.accounts_fetched + stats.accounts_reused, 100);
+    }
+
+    #[test]
+    fn serve_local() {
+        use v2::{
+            MerkleAddressBinableArgStableV1 as Addr, MinaLedgerSyncLedgerAnswerStableV2 as A,
+            MinaLedgerSyncLedgerQueryStableV1 as Q,
+        };
+
+        assert_eq!([1, 2, 8, 9, 100].map(ceil_log2), [0, 1, 3, 4, 7]);
+
+        // never synced, has no top hash
+        let mut ledger = ledger(100, &[]);
+        let content = ledger.hash_at(LEDGER_DEPTH - 7, 0).unwrap();
+        match ledger.serve_query(Q::NumAccounts) {
+            Ok(A::NumAccounts(num, hash)) => {
+                assert_eq!(num.0, 100);
+                assert_eq!(hash, content);
+            }
+            _ => panic!("must answer the number of accounts"),
+        }
+
+        let addr = |depth: i64, bytes: &[u8]| Addr(depth.into(), bytes.to_vec().into());
+        let invalid = [
+            Q::WhatChildHashes(addr(35, &[0; 5])),
+            Q::WhatChildHashes(addr(40, &[0; 5])),
+            Q::WhatChildHashes(addr(-1, &[])),
+            Q::WhatChildHashes(addr(3, &[])),
+            // the right half is empty
+            Q::WhatChildHashes(addr(1, &[0x80])),
+            Q::WhatContents(addr(10, &[0, 0])),
+            Q::WhatContents(addr(32, &13u32.to_be_bytes())),
+        ];
+        for q in invalid {
+            assert!(matches!(
+                ledger.serve_query(q),
+                Err(Info::CouldNotConstruct(_))
+            ));
+        }
+
+        // the last subtree is not full
+        match ledger.serve_query(Q::WhatContents(addr(32, &12u32.to_be_bytes()))) {
+            Ok(A::ContentsAre(accounts)) => assert_eq!(accounts.len(), 4),
+            _ => panic!("must answer the contents"),
+        }
+    }
+
+    /// The ledgers in `test_ledgers/`, synced from the server in memory, each from
+    /// scratch and each from the previous one.
+    #[test]
+    fn fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test_ledgers");
+        let fixtures = ledger_files::load_dir(dir).unwrap();
+        assert_eq!(fixtures.len(), 3);
+
+        let mut previous = None::<SnarkedLedger>;
+        for (name, mut fixture) in fixtures {
+            // recorded from the answer of a Mina node
+            assert_eq!(fixture.top_hash, Some(fixture.content_hash().unwrap()));
+            let root = fixture.hash_at(0, 0).unwrap();
+            let num = fixture.num;
+
+            let mut source = Serve::new(fixture, usize::MAX, 0);
+            let mut ledger = SnarkedLedger::empty();
+            run(ledger.sync_new(&mut source, &root)).unwrap().unwrap();
+            assert_eq!(ledger.hash_at(0, 0).unwrap().to_string(), name);
+            assert_eq!(ledger.num, num);
+
+            if let Some(mut base) = previous.take() {
+                let mut source = Serve::new(source.ledger, usize::MAX, 0);
+                let mut diff = SnarkedLedger::empty();
+                let stats = run(diff.sync_diff(&mut source, &root, &mut base))
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(diff.hash_at(0, 0).unwrap(), root);
+                assert_eq!(stats.accounts_fetched + stats.accounts_reused, num as usize);
             }
+            previous = Some(ledger);
         }
     }
 }
@@ -0,0 +1,188 @@
+//! Stake distribution of an epoch ledger, who produces the blocks of the epoch.
+//!
+//! The stake of a producer is the balance of all the accounts that delegate to it,
+//! only the accounts of the default token count. A producer wins a slot if its VRF
+//! output is below the threshold `1 - (1 - f) ^ (stake / total)`, this is also
+//! the probability to win any given slot. The total is the balance of all the accounts
+//! of the default token, delegated or not, `f` is the active slots coefficient of
+//! the network.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use mina_tree::TokenId;
+use serde::Serialize;
+
+use super::snarked_ledger::{Error, LedgerView};
+
+#[derive(Debug, Serialize)]
+pub struct Producer {
+    pub public_key: String,
+    /// Nanomina.
+    pub stake: u64,
+    pub delegators: usize,
+    pub share: f64,
+    pub win_probability: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StakeReport {
+    /// Nanomina, of all the accounts of the default token.
+    pub total_stake: u64,
+    pub accounts: usize,
+    /// The number of the producers, `producers` keeps only the largest.
+    pub delegates: usize,
+    /// The largest first.
+    pub producers: Vec<Producer>,
+}
+
+/// The probability that a producer with this share of the stake wins a slot,
+/// `f` is the active slots coefficient.
+pub fn win_probability(f: f64, share: f64) -> f64 {
+    1.0 - (1.0 - f).powf(share)
+}
+
+/// Keeps the `top` largest producers, `f` is the active slots coefficient.
+pub fn report<L>(ledger: &mut L, top: usize, f: f64) -> Result<StakeReport, Error>
+where
+    L: LedgerView + ?Sized,
+{
+    let default_token = TokenId::default();
+    let mut accounts = 0;
+    let mut total_stake = 0;
+    let mut delegates = BTreeMap::<String, (u64, usize)>::new();
+    for index in 0..(ledger.num_accounts() as u64) {
+        let Some(account) = ledger.account_at(index)? else {
+            continue;
+        };
+        accounts += 1;
+        if account.token_id != default_token {
+            continue;
+        }
+        total_stake += account.balance.as_u64();
+        let Some(delegate) = &account.delegate else {
+            continue;
+        };
+        let (stake, delegators) = delegates.entry(delegate.into_address()).or_default();
+        *stake += account.balance.as_u64();
+        *delegators += 1;
+    }
+
+    let mut producers = delegates
+        .iter()
+        .map(|(public_key, (stake, delegators))| {
+            let share = if total_stake == 0 {
+                0.0
+            } else {
+                *stake as f64 / total_stake as f64
+            };
+            Producer {
+                public_key: public_key.clone(),
+                stake: *stake,
+                delegators: *delegators,
+                share,
+                win_probability: win_probability(f, share),
+            }
+        })
+        .collect::<Vec<_>>();
+    producers.sort_by(|a, b| b.stake.cmp(&a.stake));
+    producers.truncate(top);
+
+    Ok(StakeReport {
+        total_stake,
+        accounts,
+        delegates: delegates.len(),
+        producers,
+    })
+}
+
+impl StakeReport {
+    /// One line per producer, after the header.
+    pub fn write_csv<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "public_key,stake,delegators,share,win_probability")?;
+        for p in &self.producers {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                p.public_key, p.stake, p.delegators, p.share, p.win_probability
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_curves::pasta::Fp;
+    use mina_signer::CompressedPubKey;
+    use mina_tree::{scan_state::currency::Balance, Account, AccountIndex};
+
+    use super::{super::snarked_ledger::SnarkedLedger, *};
+
+    #[test]
+    fn delegations() {
+        let a = CompressedPubKey::from_address(
+            "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        )
+        .unwrap();
+        let b = CompressedPubKey {
+            x: a.x,
+            is_odd: !a.is_odd,
+        };
+        // (delegate, balance, other token)
+        let accounts = [
+            (Some(&a), 300, false),
+            (Some(&a), 300, false),
+            (Some(&b), 200, false),
+            (None, 1000, false),
+            (Some(&b), 5000, true),
+            (Some(&a), 200, false),
+        ];
+        let mut ledger = SnarkedLedger::empty();
+        for (i, (delegate, balance, other_token)) in accounts.into_iter().enumerate() {
+            let mut account = Account::empty();
+            account.public_key = a.clone();
+            account.delegate = delegate.cloned();
+            account.balance = Balance::of_nanomina_int_exn(balance);
+            if other_token {
+                account.token_id = TokenId(Fp::from(3u64));
+            }
+            ledger
+                .inner
+                .set_at_index(AccountIndex(i as u64), Box::new(account))
+                .unwrap();
+        }
+        ledger.num = accounts.len() as u32;
+
+        let report = report(&mut ledger, 10, 0.75).unwrap();
+        // the account without a delegate counts, the other token does not
+        assert_eq!(report.total_stake, 2000);
+        assert_eq!(report.accounts, 6);
+        assert_eq!(report.delegates, 2);
+        let first = &report.producers[0];
+        assert_eq!(first.public_key, a.into_address());
+        assert_eq!((first.stake, first.delegators), (800, 3));
+        assert!((first.share - 0.4).abs() < 1e-12);
+        assert!((first.win_probability - (1.0 - 0.25f64.powf(0.4))).abs() < 1e-12);
+        assert_eq!(report.producers[1].stake, 200);
+
+        // all the stake wins every active slot
+        assert_eq!(win_probability(0.75, 1.0), 0.75);
+        assert_eq!(win_probability(0.75, 0.0), 0.0);
+
+        let mut csv = vec![];
+        report.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(&format!(",800,3,{},{}", first.share, first.win_probability)));
+    }
+}
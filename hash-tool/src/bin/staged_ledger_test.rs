@@ -3,30 +3,23 @@ use std::{fs::File, collections::BTreeMap};
 use binprot::BinProtRead;
 use mina_p2p_messages::{rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response, v2};
 use mina_tree::{
-    Account, Mask, Database, BaseLedger,
-    scan_state::{
-        scan_state::ConstraintConstants,
-        currency::{Amount, Fee},
-        transaction_logic::local_state::LocalState,
-    },
-    staged_ledger::staged_ledger::StagedLedger,
-    verifier::Verifier,
+    Account, Mask, Database, BaseLedger, scan_state::transaction_logic::local_state::LocalState,
+    staged_ledger::staged_ledger::StagedLedger, verifier::Verifier,
 };
 
-const CONSTRAINT_CONSTANTS: ConstraintConstants = ConstraintConstants {
-    sub_windows_per_window: 11,
-    ledger_depth: 35,
-    work_delay: 2,
-    block_window_duration_ms: 180000,
-    transaction_capacity_log_2: 7,
-    pending_coinbase_depth: 5,
-    coinbase_amount: Amount::from_u64(720000000000),
-    supercharged_coinbase_factor: 2,
-    account_creation_fee: Fee::from_u64(1000000000),
-    fork: None,
-};
+use mina_transport::network::Network;
 
+/// The optional arguments are the network, a preset name or a file, `berkeley` by default,
+/// and the runtime config of the Mina node that overrides the constants of the network.
 fn main() {
+    let mut args = std::env::args().skip(1);
+    let network = args.next();
+    let mut network = Network::select(network.as_deref().unwrap_or("berkeley")).unwrap();
+    if let Some(path) = args.next() {
+        network = network.with_runtime_config(path).unwrap();
+    }
+    let constraint_constants = network.constraint_constants.to_ledger().unwrap();
+
     let mut snarked_ledger_file = File::open("target/snarked_ledger").unwrap();
     let mut snarked_ledger = Mask::new_root(Database::create(35));
     for account in Vec::<Account>::binprot_read(&mut snarked_ledger_file).unwrap() {
@@ -55,7 +48,7 @@ fn main() {
 
     let mut staged_ledger = StagedLedger::of_scan_state_pending_coinbases_and_snarked_ledger(
         (),
-        &CONSTRAINT_CONSTANTS,
+        &constraint_constants,
         Verifier,
         (&scan_state).into(),
         snarked_ledger,
@@ -1,3 +1,6 @@
+use std::{fs::File, path::PathBuf};
+
+use mina_transport::chain_id::ChainIdInputs;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -14,6 +17,11 @@ enum Arg {
         #[structopt(long)]
         hash: String,
     },
+    /// Derive the chain id from the JSON file with `ChainIdInputs`
+    ChainId {
+        #[structopt(long)]
+        inputs: PathBuf,
+    },
 }
 
 fn main() {
@@ -22,6 +30,12 @@ fn main() {
         Arg::Ledger { hash } => (5, hash),
         Arg::State { hash } => (16, hash),
         Arg::PendingCoinbase { hash } => (12, hash),
+        Arg::ChainId { inputs } => {
+            let file = File::open(inputs).unwrap();
+            let inputs = serde_json::from_reader::<_, ChainIdInputs>(file).unwrap();
+            println!("{}", inputs.chain_id());
+            return;
+        }
     };
     let x = if let Ok(mut bytes) = hex::decode(format!("{hash}01")) {
         bytes.reverse();
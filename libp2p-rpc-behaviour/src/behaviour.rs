@@ -111,6 +111,20 @@ impl Behaviour {
         Ok(())
     }
 
+    /// Answer with the error `Unimplemented_rpc`, the same as the OCaml node does
+    /// for the methods it does not serve.
+    pub fn respond_unimplemented(
+        &mut self,
+        peer_id: PeerId,
+        stream_id: StreamId,
+        id: i64,
+        tag: &str,
+        version: i32,
+    ) {
+        let bytes = unimplemented_rpc(id, tag.as_bytes(), version);
+        self.dispatch_command(peer_id, Command::Send { stream_id, bytes });
+    }
+
     pub fn query<M>(
         &mut self,
         peer_id: PeerId,
@@ -138,6 +152,64 @@ impl Behaviour {
     }
 }
 
+/// `bin_prot` encoding of OCaml `int`.
+fn write_int(bytes: &mut Vec<u8>, x: i64) {
+    if (0..0x80).contains(&x) {
+        bytes.push(x as u8);
+    } else if (-0x80..0).contains(&x) {
+        bytes.push(0xff);
+        bytes.push(x as u8);
+    } else if (-0x8000..0x8000).contains(&x) {
+        bytes.push(0xfe);
+        bytes.extend_from_slice(&(x as i16).to_le_bytes());
+    } else if (-0x8000_0000..0x8000_0000).contains(&x) {
+        bytes.push(0xfd);
+        bytes.extend_from_slice(&(x as i32).to_le_bytes());
+    } else {
+        bytes.push(0xfc);
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+/// `bin_prot` encoding of the length of a string.
+fn write_nat0(bytes: &mut Vec<u8>, x: usize) {
+    if x < 0x80 {
+        bytes.push(x as u8);
+    } else if x < 0x10000 {
+        bytes.push(0xfe);
+        bytes.extend_from_slice(&(x as u16).to_le_bytes());
+    } else if x < 0x1_0000_0000 {
+        bytes.push(0xfd);
+        bytes.extend_from_slice(&(x as u32).to_le_bytes());
+    } else {
+        bytes.push(0xfc);
+        bytes.extend_from_slice(&(x as u64).to_le_bytes());
+    }
+}
+
+/// The message `Response { id; data = Error (Unimplemented_rpc (tag, `Version version)) }`
+/// with the length prefix.
+fn unimplemented_rpc(id: i64, tag: &[u8], version: i32) -> Vec<u8> {
+    const RESPONSE: u8 = 2;
+    const ERROR: u8 = 1;
+    const UNIMPLEMENTED_RPC: u8 = 4;
+    // `bin_prot` writes the hash of the polymorphic variant `Version as `(hash << 1) | 1`
+    const VERSION: u32 = 0x94861df1;
+
+    let mut bytes = vec![0; 8];
+    bytes.push(RESPONSE);
+    write_int(&mut bytes, id);
+    bytes.push(ERROR);
+    bytes.push(UNIMPLEMENTED_RPC);
+    write_nat0(&mut bytes, tag.len());
+    bytes.extend_from_slice(tag);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_int(&mut bytes, version.into());
+    let len = (bytes.len() - 8) as u64;
+    bytes[..8].clone_from_slice(&len.to_le_bytes());
+    bytes
+}
+
 impl NetworkBehaviour for Behaviour {
     type ConnectionHandler = Handler;
     type OutEvent = (PeerId, Event);
@@ -228,3 +300,36 @@ impl NetworkBehaviour for Behaviour {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use binprot::BinProtRead;
+    use mina_p2p_messages::rpc_kernel::{MessageHeader, ResponseHeader, ResponsePayload};
+
+    use super::*;
+
+    #[test]
+    fn unimplemented() {
+        let bytes = unimplemented_rpc(300, b"get_some_initial_peers", 1);
+        let expected = [
+            &[0x22, 0, 0, 0, 0, 0, 0, 0][..],
+            &[0x02, 0xfe, 0x2c, 0x01, 0x01, 0x04, 0x16],
+            b"get_some_initial_peers",
+            &[0xf1, 0x1d, 0x86, 0x94, 0x01],
+        ]
+        .concat();
+        assert_eq!(bytes, expected);
+
+        // the client side of this crate must read it as an error
+        let mut slice = &bytes[8..];
+        let header = MessageHeader::binprot_read(&mut slice).unwrap();
+        assert!(matches!(
+            header,
+            MessageHeader::Response(ResponseHeader { id: 300 })
+        ));
+        assert!(ResponsePayload::<()>::binprot_read(&mut slice)
+            .unwrap()
+            .0
+            .is_err());
+    }
+}
@@ -1,32 +1,15 @@
-use std::{
-    fs::File,
-    io::{Read, Write},
-};
+use std::path::Path;
 
 use libp2p::swarm::SwarmEvent;
-use mina_transport::{futures::StreamExt, Keypair};
+use mina_transport::futures::StreamExt;
 use libp2p_rpc_behaviour::{Event, BehaviourBuilder};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let local_key = match File::open("target/identity") {
-        Ok(mut file) => {
-            let mut bytes = [0; 64];
-            file.read_exact(&mut bytes).unwrap();
-            Keypair::ed25519_from_bytes(bytes).unwrap()
-        }
-        Err(_) => {
-            let k = mina_transport::generate_identity();
-            let bytes = k.clone().into_ed25519().unwrap().encode();
-            File::create("target/identity")
-                .unwrap()
-                .write_all(&bytes)
-                .unwrap();
-            k
-        }
-    };
+    let local_key = mina_transport::keys::load_or_generate(Some(Path::new("target/identity")))
+        .expect("cannot load the key");
 
     let peers = [
         // "/ip4/135.181.217.23/tcp/30737/p2p/12D3KooWAVvZjW5m5LmhJrCUq2VtvG3drAsWxewMobgoUpewtqcp",
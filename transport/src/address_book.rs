@@ -0,0 +1,603 @@
+//! Known peers with their addresses, the time they were last seen and the number of failed
+//! dials in a row.
+//!
+//! The peers dialed with an explicit address, like the `peers` of [`crate::swarm`], are kept
+//! connected: when such a connection is lost the book dials the peer again, waiting twice as
+//! long after each failure. The book can be stored in a JSON file and loaded on the next run,
+//! the peers kept connected before are dialed right away. Only such peers are in the book,
+//! at most [`AddressBook::with_max_peers`], the peers seen the longest time ago go first.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use libp2p::{
+    core::{ConnectedPoint, Endpoint},
+    multiaddr::Protocol,
+    swarm::{
+        derive_prelude::ConnectionEstablished,
+        dial_opts::{DialOpts, PeerCondition},
+        dummy, ConnectionClosed, ConnectionDenied, ConnectionId, DialError, DialFailure, FromSwarm,
+        NetworkBehaviour, PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DEFAULT_MAX_PEERS: usize = 1024;
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("bad peer id {0}")]
+    PeerId(String),
+}
+
+/// An entry of the book as it is stored in the file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Record {
+    pub addrs: BTreeSet<Multiaddr>,
+    /// Seconds since the unix epoch.
+    pub last_seen: Option<u64>,
+    /// Failed dials since the last successful connection.
+    pub failures: u32,
+    /// Dial the peer again when the connection is lost.
+    pub keep: bool,
+}
+
+struct Entry {
+    record: Record,
+    next_dial: Option<Instant>,
+}
+
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    peers: BTreeMap<PeerId, Entry>,
+    connected: BTreeSet<PeerId>,
+    dialing: BTreeSet<PeerId>,
+    backoff: Duration,
+    max_backoff: Duration,
+    max_peers: usize,
+    timer: Option<tokio::time::Interval>,
+    dirty: bool,
+    saved: Instant,
+    /// The number of the last snapshot of the book, and of the last one written into
+    /// the file, the file is written by blocking tasks, not by the poll of the swarm.
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        AddressBook {
+            path: None,
+            peers: BTreeMap::default(),
+            connected: BTreeSet::default(),
+            dialing: BTreeSet::default(),
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_peers: DEFAULT_MAX_PEERS,
+            timer: None,
+            dirty: false,
+            saved: Instant::now(),
+            generation: 0,
+            written: Arc::default(),
+        }
+    }
+}
+
+/// The records in the format of the file.
+struct Snapshot {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl Snapshot {
+    /// Through a temporary file, so the book is never half written. A snapshot older than
+    /// the one in the file is not written.
+    fn write(self) -> Result<(), Error> {
+        let mut written = self.written.lock().unwrap_or_else(PoisonError::into_inner);
+        if *written > self.generation {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        File::create(&tmp)?.write_all(&self.bytes)?;
+        std::fs::rename(tmp, &self.path)?;
+        *written = self.generation;
+        Ok(())
+    }
+}
+
+/// The address without the trailing `/p2p/...`.
+fn strip_peer_id(addr: &Multiaddr) -> Multiaddr {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl AddressBook {
+    /// Load the book from the file, or start an empty one if the file does not exist.
+    /// The book is written back into the same file.
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut book = AddressBook::default();
+        book.path = Some(path.to_owned());
+        let records = match File::open(path) {
+            Ok(file) => serde_json::from_reader::<_, BTreeMap<String, Record>>(file)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(book),
+            Err(err) => return Err(err.into()),
+        };
+        let now = Instant::now();
+        for (peer_id, record) in records {
+            let peer_id = peer_id.parse().map_err(|_| Error::PeerId(peer_id))?;
+            if !record.keep {
+                continue;
+            }
+            let next_dial = Some(now);
+            book.peers.insert(peer_id, Entry { record, next_dial });
+        }
+        book.evict(None);
+        Ok(book)
+    }
+
+    /// Write the book into the file it was loaded from, does nothing for an in-memory book.
+    pub fn save(&mut self) -> Result<(), Error> {
+        match self.snapshot()? {
+            Some(snapshot) => snapshot.write(),
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&mut self) -> Result<Option<Snapshot>, Error> {
+        self.dirty = false;
+        self.saved = Instant::now();
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let records = self
+            .peers
+            .iter()
+            .map(|(peer_id, entry)| (peer_id.to_base58(), &entry.record))
+            .collect::<BTreeMap<_, _>>();
+        self.generation += 1;
+        Ok(Some(Snapshot {
+            path: path.clone(),
+            bytes: serde_json::to_vec_pretty(&records)?,
+            generation: self.generation,
+            written: self.written.clone(),
+        }))
+    }
+
+    /// Same as [`AddressBook::save`], but the file is written by a blocking task.
+    fn save_in_background(&mut self) {
+        match self.snapshot() {
+            Ok(Some(snapshot)) => {
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = snapshot.write() {
+                        log::error!("cannot save the address book, {err}");
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("cannot save the address book, {err}"),
+        }
+    }
+
+    /// Wait `backoff` after the first failure, twice as long after each next one,
+    /// but not longer than `max`.
+    pub fn with_backoff(mut self, backoff: Duration, max: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Keep at most this many peers, the default is 1024.
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self.evict(None);
+        self
+    }
+
+    /// Keep the peer connected, the address must contain `/p2p/...`.
+    /// Returns `false` if it does not.
+    pub fn add(&mut self, addr: &Multiaddr) -> bool {
+        match crate::discovery::peer_id(addr) {
+            Some(peer_id) => {
+                self.insert(peer_id, [addr], true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&Record> {
+        self.peers.get(peer_id).map(|entry| &entry.record)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Record)> {
+        self.peers
+            .iter()
+            .map(|(peer_id, entry)| (peer_id, &entry.record))
+    }
+
+    fn insert<'a, I>(&mut self, peer_id: PeerId, addrs: I, keep: bool)
+    where
+        I: IntoIterator<Item = &'a Multiaddr>,
+    {
+        let entry = self.peers.entry(peer_id).or_insert_with(|| Entry {
+            record: Record::default(),
+            next_dial: None,
+        });
+        for addr in addrs {
+            self.dirty |= entry.record.addrs.insert(strip_peer_id(addr));
+        }
+        if keep && !entry.record.keep {
+            entry.record.keep = true;
+            self.dirty = true;
+        }
+        if self.peers.len() > self.max_peers {
+            self.evict(Some(&peer_id));
+        }
+    }
+
+    /// Drop the peers seen the longest time ago, never seen first, until the book fits.
+    /// The connected peers and the `protected` one stay.
+    fn evict(&mut self, protected: Option<&PeerId>) {
+        while self.peers.len() > self.max_peers {
+            let oldest = self
+                .peers
+                .iter()
+                .filter(|(peer_id, _)| {
+                    !self.connected.contains(peer_id) && Some(*peer_id) != protected
+                })
+                .min_by_key(|(_, entry)| (entry.record.last_seen, u32::MAX - entry.record.failures))
+                .map(|(peer_id, _)| *peer_id);
+            let Some(peer_id) = oldest else {
+                break;
+            };
+            log::debug!("forget {peer_id}, the address book is full");
+            self.peers.remove(&peer_id);
+            self.dirty = true;
+        }
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return self.backoff.min(self.max_backoff);
+        }
+        self.backoff
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(self.max_backoff)
+    }
+
+    fn schedule(&mut self, peer_id: &PeerId) {
+        let Some(entry) = self.peers.get(peer_id) else {
+            return;
+        };
+        if !entry.record.keep {
+            return;
+        }
+        let delay = self.delay(entry.record.failures);
+        log::debug!("dial {peer_id} again in {delay:?}");
+        if let Some(entry) = self.peers.get_mut(peer_id) {
+            entry.next_dial = Some(Instant::now() + delay);
+        }
+    }
+
+    fn next_to_dial(&mut self) -> Option<DialOpts> {
+        let now = Instant::now();
+        let (connected, dialing) = (&self.connected, &self.dialing);
+        let (peer_id, entry) = self.peers.iter_mut().find(|(peer_id, entry)| {
+            entry.next_dial.is_some_and(|time| time <= now)
+                && !connected.contains(peer_id)
+                && !dialing.contains(peer_id)
+        })?;
+        entry.next_dial = None;
+        self.dialing.insert(*peer_id);
+        let addrs = entry.record.addrs.iter().cloned().collect();
+        Some(
+            DialOpts::peer_id(*peer_id)
+                .addresses(addrs)
+                .condition(PeerCondition::Disconnected)
+                .build(),
+        )
+    }
+}
+
+impl Drop for AddressBook {
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Err(err) = self.save() {
+                log::error!("cannot save the address book, {err}");
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for AddressBook {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type OutEvent = void::Void;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let Some(peer_id) = maybe_peer else {
+            return Ok(vec![]);
+        };
+        self.dialing.insert(peer_id);
+        // the peer is dialed by its address on purpose, keep it connected
+        if !addresses.is_empty() {
+            self.insert(peer_id, addresses, true);
+        }
+        let addrs = self
+            .peers
+            .get(&peer_id)
+            .map_or(vec![], |entry| entry.record.addrs.iter().cloned().collect());
+        Ok(addrs)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id, endpoint, ..
+            }) => {
+                self.dialing.remove(&peer_id);
+                self.connected.insert(peer_id);
+                // a new address of a peer that is kept, the other peers are not in the book
+                if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                    if self.peers.contains_key(&peer_id) {
+                        self.insert(peer_id, [address], false);
+                    }
+                }
+                if let Some(entry) = self.peers.get_mut(&peer_id) {
+                    entry.record.last_seen = Some(now_secs());
+                    entry.record.failures = 0;
+                    entry.next_dial = None;
+                    self.dirty = true;
+                }
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established: 0,
+                ..
+            }) => {
+                self.connected.remove(&peer_id);
+                if let Some(entry) = self.peers.get_mut(&peer_id) {
+                    entry.record.last_seen = Some(now_secs());
+                    self.dirty = true;
+                }
+                self.schedule(&peer_id);
+            }
+            FromSwarm::DialFailure(DialFailure {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            }) => {
+                // the dial is not even started, the peer is connected or being dialed
+                if matches!(error, DialError::DialPeerConditionFalse(_)) {
+                    return;
+                }
+                self.dialing.remove(&peer_id);
+                if self.connected.contains(&peer_id) {
+                    return;
+                }
+                if let Some(entry) = self.peers.get_mut(&peer_id) {
+                    entry.record.failures = entry.record.failures.saturating_add(1);
+                    self.dirty = true;
+                }
+                self.schedule(&peer_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        let timer = self
+            .timer
+            .get_or_insert_with(|| tokio::time::interval(TICK));
+        while timer.poll_tick(cx).is_ready() {}
+
+        if self.dirty && self.saved.elapsed() >= SAVE_INTERVAL {
+            self.save_in_background();
+        }
+
+        match self.next_to_dial() {
+            Some(opts) => Poll::Ready(ToSwarm::Dial { opts }),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        futures::{future, StreamExt},
+        swarm::{keep_alive, SwarmEvent},
+        Swarm,
+    };
+
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let book =
+            AddressBook::default().with_backoff(Duration::from_secs(1), Duration::from_secs(10));
+        let delays = (0..6).map(|failures| book.delay(failures).as_secs());
+        assert_eq!(delays.collect::<Vec<_>>(), [1, 1, 2, 4, 8, 10]);
+    }
+
+    #[test]
+    fn persistent() {
+        let path = std::env::temp_dir().join(format!("address-book-{}.json", std::process::id()));
+        let peer_id = PeerId::random();
+        let addr = "/ip4/10.0.0.1/tcp/8302"
+            .parse::<Multiaddr>()
+            .unwrap()
+            .with(Protocol::P2p(peer_id.into()));
+
+        let mut book = AddressBook::load(&path).unwrap();
+        assert!(book.add(&addr));
+        book.save().unwrap();
+        drop(book);
+
+        let book = AddressBook::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let record = book.get(&peer_id).unwrap();
+        assert!(record.keep);
+        assert_eq!(
+            record.addrs.iter().collect::<Vec<_>>(),
+            ["/ip4/10.0.0.1/tcp/8302".parse::<Multiaddr>().unwrap()]
+                .iter()
+                .collect::<Vec<_>>(),
+        );
+        assert!(book.peers[&peer_id].next_dial.is_some());
+    }
+
+    #[test]
+    fn capped() {
+        let addr = |peer_id: PeerId| {
+            "/ip4/10.0.0.1/tcp/8302"
+                .parse::<Multiaddr>()
+                .unwrap()
+                .with(Protocol::P2p(peer_id.into()))
+        };
+        let mut book = AddressBook::default().with_max_peers(3);
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for (peer_id, last_seen) in peers.iter().zip([100, 50, 10]) {
+            assert!(book.add(&addr(*peer_id)));
+            book.peers.get_mut(peer_id).unwrap().record.last_seen = Some(last_seen);
+        }
+        // the last one is seen the longest time ago, but it is connected
+        book.connected.insert(peers[2]);
+        let new = PeerId::random();
+        assert!(book.add(&addr(new)));
+        assert_eq!(book.iter().count(), 3);
+        assert!(book.get(&peers[1]).is_none());
+        for peer_id in [peers[0], peers[2], new] {
+            assert!(book.get(&peer_id).is_some());
+        }
+
+        // a peer connects before it is in the book, it stays connected
+        let inbound = PeerId::random();
+        book.connected.insert(inbound);
+        book.insert(inbound, [&addr(inbound)], false);
+        assert!(book.connected.contains(&inbound));
+        assert!(book.get(&inbound).is_some());
+        assert_eq!(book.iter().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let mut listener = crate::Builder::new(crate::generate_identity(), b"test")
+            .memory()
+            .build(["/memory/0".parse().unwrap()], [], keep_alive::Behaviour);
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = listener.select_next_some().await {
+                break address.with(Protocol::P2p((*listener.local_peer_id()).into()));
+            }
+        };
+        let listener_id = *listener.local_peer_id();
+
+        let book =
+            AddressBook::default().with_backoff(Duration::from_millis(100), Duration::from_secs(1));
+        let mut dialer = crate::Builder::new(crate::generate_identity(), b"test")
+            .memory()
+            .build([], [addr], book);
+
+        async fn connected(
+            listener: &mut Swarm<keep_alive::Behaviour>,
+            dialer: &mut Swarm<AddressBook>,
+        ) {
+            future::poll_fn(|cx| {
+                while let Poll::Ready(Some(_)) = listener.poll_next_unpin(cx) {}
+                while let Poll::Ready(Some(_)) = dialer.poll_next_unpin(cx) {}
+                let peer_id = *listener.local_peer_id();
+                if dialer.behaviour().connected.contains(&peer_id) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+
+        let timeout = Duration::from_secs(10);
+        tokio::time::timeout(timeout, connected(&mut listener, &mut dialer))
+            .await
+            .expect("must connect");
+        let dialer_id = *dialer.local_peer_id();
+        listener.disconnect_peer_id(dialer_id).unwrap();
+        while dialer.is_connected(&listener_id) {
+            future::select(listener.select_next_some(), dialer.select_next_some()).await;
+        }
+        tokio::time::timeout(timeout, connected(&mut listener, &mut dialer))
+            .await
+            .expect("must reconnect");
+        assert!(dialer.behaviour().get(&listener_id).unwrap().keep);
+    }
+}
@@ -0,0 +1,337 @@
+//! Capture of the decrypted traffic of each connection.
+//!
+//! Every connection gets its own file in the capture directory. The file starts with a header
+//! describing the connection, followed by records, one record per yamux frame:
+//!
+//! ```text
+//! header: magic "MCAP", version u8, dialer u8,
+//!         peer_id_len u8, peer_id, remote_addr_len u16, remote_addr
+//! record: timestamp_us u64, incoming u8, stream_id u32, len u32, frame (header and payload)
+//! ```
+//!
+//! All integers are little endian. Use [`Reader`] to parse the file back.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use libp2p::{core::ConnectedPoint, Multiaddr, PeerId};
+
+const MAGIC: [u8; 4] = *b"MCAP";
+const VERSION: u8 = 1;
+
+/// Yamux frame header is 12 bytes: version, type, flags, stream id, length.
+const YAMUX_HEADER_LEN: usize = 12;
+const YAMUX_TYPE_DATA: u8 = 0;
+
+/// Where to store the traffic. Cheap to clone, every clone writes into the same directory.
+#[derive(Clone)]
+pub struct Capture {
+    dir: Arc<PathBuf>,
+    counter: Arc<AtomicU64>,
+}
+
+impl Capture {
+    pub fn new<P>(dir: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Capture {
+            dir: Arc::new(dir.as_ref().to_owned()),
+            counter: Arc::default(),
+        })
+    }
+
+    pub(crate) fn connection(
+        &self,
+        peer_id: &PeerId,
+        endpoint: &ConnectedPoint,
+    ) -> Option<Recorder> {
+        let id = self.counter.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{id:06}_{peer_id}.bin"));
+        match Recorder::create(&path, peer_id, endpoint) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => {
+                log::warn!("cannot capture connection to {peer_id}, {err}");
+                None
+            }
+        }
+    }
+}
+
+pub(crate) struct Recorder {
+    file: BufWriter<File>,
+    incoming: Splitter,
+    outgoing: Splitter,
+}
+
+impl Recorder {
+    fn create(path: &Path, peer_id: &PeerId, endpoint: &ConnectedPoint) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let peer_id = peer_id.to_bytes();
+        let remote_addr = endpoint.get_remote_address().to_vec();
+        file.write_all(&MAGIC)?;
+        file.write_all(&[VERSION, endpoint.is_dialer() as u8, peer_id.len() as u8])?;
+        file.write_all(&peer_id)?;
+        file.write_all(&(remote_addr.len() as u16).to_le_bytes())?;
+        file.write_all(&remote_addr)?;
+
+        Ok(Recorder {
+            file,
+            incoming: Splitter::default(),
+            outgoing: Splitter::default(),
+        })
+    }
+
+    pub fn on_read(&mut self, bytes: &[u8]) {
+        self.record(true, bytes)
+    }
+
+    pub fn on_write(&mut self, bytes: &[u8]) {
+        self.record(false, bytes)
+    }
+
+    fn record(&mut self, incoming: bool, bytes: &[u8]) {
+        let splitter = if incoming {
+            &mut self.incoming
+        } else {
+            &mut self.outgoing
+        };
+        splitter.buf.extend_from_slice(bytes);
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        while let Some((stream_id, frame)) = splitter.next_frame() {
+            let result = (|| {
+                self.file.write_all(&time.to_le_bytes())?;
+                self.file.write_all(&[incoming as u8])?;
+                self.file.write_all(&stream_id.to_le_bytes())?;
+                self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+                self.file.write_all(&frame)
+            })();
+            if let Err(err) = result {
+                log::warn!("cannot write capture, {err}");
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(err) = self.file.flush() {
+            log::warn!("cannot flush capture, {err}");
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Cuts the byte stream into yamux frames.
+#[derive(Default)]
+struct Splitter {
+    buf: Vec<u8>,
+}
+
+impl Splitter {
+    fn next_frame(&mut self) -> Option<(u32, Vec<u8>)> {
+        if self.buf.len() < YAMUX_HEADER_LEN {
+            return None;
+        }
+        let header = &self.buf[..YAMUX_HEADER_LEN];
+        let stream_id = u32::from_be_bytes(header[4..8].try_into().expect("cannot fail"));
+        let len = u32::from_be_bytes(header[8..12].try_into().expect("cannot fail")) as usize;
+        // only data frames carry a payload, for other frames the length has other meaning
+        let frame_len = if header[1] == YAMUX_TYPE_DATA {
+            YAMUX_HEADER_LEN + len
+        } else {
+            YAMUX_HEADER_LEN
+        };
+        if self.buf.len() < frame_len {
+            return None;
+        }
+        let rest = self.buf.split_off(frame_len);
+        let frame = std::mem::replace(&mut self.buf, rest);
+        Some((stream_id, frame))
+    }
+}
+
+/// A single yamux frame read from a capture file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub time: SystemTime,
+    pub incoming: bool,
+    pub stream_id: u32,
+    /// The whole frame including the 12 bytes yamux header.
+    pub bytes: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn payload(&self) -> &[u8] {
+        self.bytes.get(YAMUX_HEADER_LEN..).unwrap_or_default()
+    }
+}
+
+/// Reads a capture file written for a single connection.
+pub struct Reader<R> {
+    inner: R,
+    pub dialer: bool,
+    pub peer_id: PeerId,
+    pub remote_addr: Multiaddr,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R> Reader<R>
+where
+    R: Read,
+{
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+        let mut header = [0; 7];
+        inner.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid("not a capture file"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid("unsupported capture version"));
+        }
+        let dialer = header[5] != 0;
+        let mut peer_id = vec![0; header[6] as usize];
+        inner.read_exact(&mut peer_id)?;
+        let peer_id = PeerId::from_bytes(&peer_id).map_err(|_| invalid("bad peer id"))?;
+        let mut len = [0; 2];
+        inner.read_exact(&mut len)?;
+        let mut remote_addr = vec![0; u16::from_le_bytes(len) as usize];
+        inner.read_exact(&mut remote_addr)?;
+        let remote_addr = Multiaddr::try_from(remote_addr).map_err(|_| invalid("bad address"))?;
+
+        Ok(Reader {
+            inner,
+            dialer,
+            peer_id,
+            remote_addr,
+        })
+    }
+
+    /// `None` at the end of the file, a header cut in the middle is an error.
+    fn read_chunk(&mut self) -> io::Result<Option<Chunk>> {
+        let mut header = [0; 17];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.inner.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    let msg = "the chunk header is truncated";
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+                }
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let time = u64::from_le_bytes(header[..8].try_into().expect("cannot fail"));
+        let stream_id = u32::from_le_bytes(header[9..13].try_into().expect("cannot fail"));
+        let len = u32::from_le_bytes(header[13..].try_into().expect("cannot fail"));
+        let mut bytes = vec![0; len as usize];
+        self.inner.read_exact(&mut bytes)?;
+
+        Ok(Some(Chunk {
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(time),
+            incoming: header[8] != 0,
+            stream_id,
+            bytes,
+        }))
+    }
+}
+
+impl<R> Iterator for Reader<R>
+where
+    R: Read,
+{
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_chunk().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::core::Endpoint;
+
+    use super::*;
+
+    fn frame(ty: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0, ty, 0, 0];
+        bytes.extend_from_slice(&stream_id.to_be_bytes());
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("mina-capture-{}", std::process::id()));
+        let capture = Capture::new(&dir).unwrap();
+        let peer_id = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/8302".parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        };
+
+        let data = frame(0, 3, b"hello");
+        let ping = frame(2, 0, &[]);
+        {
+            let mut recorder = capture.connection(&peer_id, &endpoint).unwrap();
+            // the frame arrives in two pieces
+            recorder.on_read(&data[..7]);
+            recorder.on_read(&data[7..]);
+            recorder.on_write(&ping[..12]);
+        }
+
+        let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let bytes = fs::read(&path).unwrap();
+        let reader = Reader::open(path).unwrap();
+        assert!(reader.dialer);
+        assert_eq!(reader.peer_id, peer_id);
+        assert_eq!(reader.remote_addr, *endpoint.get_remote_address());
+        let chunks = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].incoming);
+        assert_eq!(chunks[0].stream_id, 3);
+        assert_eq!(chunks[0].payload(), b"hello");
+        assert!(!chunks[1].incoming);
+        assert_eq!(chunks[1].bytes, ping);
+
+        // cut in the header of the last chunk
+        let cut = bytes.len() - chunks[1].bytes.len() - 5;
+        let chunks = Reader::new(&bytes[..cut]).unwrap().collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        let err = chunks[1].as_ref().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,150 @@
+//! Derivation of the chain id, the same as the Mina node does.
+//!
+//! ```text
+//! chain_id = blake2b_256_hex(
+//!     genesis_state_hash (base58)
+//!     ++ concat(md5_hex(digest) for each constraint system)
+//!     ++ genesis_constants_hash
+//!     ++ md5_hex(protocol_transaction_version)
+//!     ++ md5_hex(protocol_network_version)
+//! )
+//! ```
+//!
+//! The inputs are what `mina advanced chain-id-inputs` prints.
+
+use blake2::{
+    digest::{generic_array::GenericArray, Update, VariableOutput},
+    Blake2bVar,
+};
+use chrono::NaiveDateTime;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisConstants {
+    pub k: u32,
+    pub slots_per_epoch: u32,
+    pub slots_per_sub_window: u32,
+    pub delta: u32,
+    pub txpool_max_size: u32,
+    /// Milliseconds since the unix epoch.
+    pub genesis_state_timestamp: i64,
+}
+
+impl GenesisConstants {
+    /// The hash is computed over the decimal numbers and the genesis timestamp in the format of `Core.Time.to_string_abs ~zone:utc`.
+    pub fn hash(&self) -> String {
+        let numbers = [
+            self.k,
+            self.slots_per_epoch,
+            self.slots_per_sub_window,
+            self.delta,
+            self.txpool_max_size,
+        ];
+        let mut s = numbers
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<String>();
+        let timestamp =
+            NaiveDateTime::from_timestamp_millis(self.genesis_state_timestamp).unwrap_or_default();
+        s.push_str(&timestamp.format("%Y-%m-%d %H:%M:%S%.6fZ").to_string());
+        blake2b_hex(s.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainIdInputs {
+    /// Base58check string, like `3NK...`.
+    pub genesis_state_hash: String,
+    pub genesis_constants: GenesisConstants,
+    /// Md5 digests of the constraint systems in hex, in the order the node lists them.
+    pub constraint_system_digests: Vec<String>,
+    pub protocol_transaction_version: u32,
+    pub protocol_network_version: u32,
+}
+
+impl ChainIdInputs {
+    /// The chain id as hex string, use its bytes as `chain_id` for [`crate::swarm`].
+    pub fn chain_id(&self) -> String {
+        let mut s = self.genesis_state_hash.clone();
+        for digest in &self.constraint_system_digests {
+            s.push_str(digest);
+        }
+        s.push_str(&self.genesis_constants.hash());
+        s.push_str(&md5_hex(
+            self.protocol_transaction_version.to_string().as_bytes(),
+        ));
+        s.push_str(&md5_hex(
+            self.protocol_network_version.to_string().as_bytes(),
+        ));
+        blake2b_hex(s.as_bytes())
+    }
+}
+
+fn blake2b_hex(data: &[u8]) -> String {
+    let mut hash = GenericArray::<u8, blake2::digest::consts::U32>::default();
+    Blake2bVar::new(32)
+        .expect("valid constant")
+        .chain(data)
+        .finalize_variable(&mut hash)
+        .expect("good buffer size");
+    hex::encode(hash)
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    hex::encode(Md5::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected values of the made up inputs are computed independently with
+    // Python's `hashlib` following the OCaml code of the Mina node.
+
+    fn constants() -> GenesisConstants {
+        GenesisConstants {
+            k: 290,
+            slots_per_epoch: 7140,
+            slots_per_sub_window: 7,
+            delta: 0,
+            txpool_max_size: 3000,
+            genesis_state_timestamp: 1706882461000,
+        }
+    }
+
+    #[test]
+    fn genesis_constants_hash() {
+        assert_eq!(
+            constants().hash(),
+            "e1731ca09f088d8d806f2b4fc126e1c82a167a3258c193b74b00d980de2c7eaf",
+        );
+    }
+
+    #[test]
+    fn chain_id() {
+        let inputs = ChainIdInputs {
+            genesis_state_hash: "3NK512ryRJvj1TUHGgcPMBMh7kqAx8ZFgTn7CHGyCXDqGzQjhR4L".to_owned(),
+            genesis_constants: constants(),
+            constraint_system_digests: vec![
+                md5_hex(b"transaction-snark"),
+                md5_hex(b"blockchain-snark"),
+            ],
+            protocol_transaction_version: 3,
+            protocol_network_version: 0,
+        };
+        assert_eq!(
+            inputs.chain_id(),
+            "15656ef20ba56adc0bcb543796a815ab9124282e2d7b6fe460819b326baa1cfe",
+        );
+    }
+
+    /// The published chain id of the mainnet after the Berkeley hard fork.
+    #[test]
+    fn mainnet_chain_id() {
+        assert_eq!(
+            crate::network::mainnet_chain_id_inputs().chain_id(),
+            "a7351abc7ddf2ea92d1b38cc8e636c271c1dfd2c081c637f62ebc2af34eb7cc1",
+        );
+    }
+}
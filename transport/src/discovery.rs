@@ -0,0 +1,356 @@
+//! Peer discovery compatible with the DHT of the Mina network.
+//!
+//! Kademlia runs under the protocol name `/coda/kad/1.0.0`, the routing table is filled
+//! from the seeds and from the listen addresses reported by identify. The behaviour dials
+//! the peers it knows until the number of connections reaches the target.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, VecDeque},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    core::Endpoint,
+    identify,
+    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
+    multiaddr::Protocol,
+    swarm::{
+        derive_prelude::ConnectionEstablished,
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionClosed, ConnectionDenied, ConnectionId, DialFailure, FromSwarm, NetworkBehaviour,
+        PollParameters, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+
+use super::Keypair;
+
+pub const KADEMLIA_PROTOCOL: &[u8] = b"/coda/kad/1.0.0";
+/// The Go implementation of libp2p, which the Mina node uses, sends this version.
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "ipfs/0.1.0";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Extract the peer id from the `/p2p/...` component of the address.
+pub fn peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+/// The behaviours the discovery is made of.
+#[derive(NetworkBehaviour)]
+pub struct Inner {
+    kademlia: Kademlia<MemoryStore>,
+    identify: identify::Behaviour,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    /// A new peer is added into the routing table.
+    Discovered(PeerId),
+}
+
+pub struct Discovery {
+    inner: Inner,
+    target: usize,
+    interval: Duration,
+    timer: Option<tokio::time::Interval>,
+    connected: BTreeSet<PeerId>,
+    dialing: BTreeSet<PeerId>,
+    /// Peers that failed to dial, not tried again until the next round.
+    unreachable: BTreeSet<PeerId>,
+    queue: VecDeque<ToSwarm<Event, THandlerInEvent<Self>>>,
+}
+
+impl Discovery {
+    /// Keep at least `target` connections, with zero the behaviour never dials by itself,
+    /// but still answers the DHT queries of other peers.
+    pub fn new(local_key: &Keypair, target: usize) -> Self {
+        let local_peer_id = local_key.public().to_peer_id();
+        let mut config = KademliaConfig::default();
+        config.set_protocol_names(vec![Cow::Borrowed(KADEMLIA_PROTOCOL)]);
+        let kademlia =
+            Kademlia::with_config(local_peer_id, MemoryStore::new(local_peer_id), config);
+        let identify = identify::Behaviour::new(
+            identify::Config::new(IDENTIFY_PROTOCOL_VERSION.to_owned(), local_key.public())
+                .with_agent_version(concat!("openmina/", env!("CARGO_PKG_VERSION")).to_owned()),
+        );
+
+        Discovery {
+            inner: Inner { kademlia, identify },
+            target,
+            interval: DEFAULT_INTERVAL,
+            timer: None,
+            connected: BTreeSet::default(),
+            dialing: BTreeSet::default(),
+            unreachable: BTreeSet::default(),
+            queue: VecDeque::default(),
+        }
+    }
+
+    /// How often to refresh the routing table.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Add the seed into the routing table, the address must contain `/p2p/...`.
+    /// Returns `false` if it does not.
+    pub fn add_seed(&mut self, addr: &Multiaddr) -> bool {
+        match peer_id(addr) {
+            Some(peer_id) => {
+                self.inner.kademlia.add_address(&peer_id, addr.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = &PeerId> {
+        self.connected.iter()
+    }
+
+    pub fn kademlia(&mut self) -> &mut Kademlia<MemoryStore> {
+        &mut self.inner.kademlia
+    }
+
+    fn on_event(&mut self, event: InnerEvent) {
+        match event {
+            InnerEvent::Identify(identify::Event::Received { peer_id, info }) => {
+                let supports_kad = info
+                    .protocols
+                    .iter()
+                    .any(|p| p.as_bytes() == KADEMLIA_PROTOCOL);
+                if supports_kad {
+                    for addr in info.listen_addrs {
+                        self.inner.kademlia.add_address(&peer_id, addr);
+                    }
+                }
+            }
+            InnerEvent::Kademlia(KademliaEvent::RoutingUpdated {
+                peer,
+                is_new_peer: true,
+                ..
+            }) => {
+                log::debug!("discovered {peer}");
+                self.queue
+                    .push_back(ToSwarm::GenerateEvent(Event::Discovered(peer)));
+            }
+            _ => {}
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.unreachable.clear();
+        if self.target == 0 {
+            return;
+        }
+        match self.inner.kademlia.bootstrap() {
+            Ok(_) => log::debug!("refresh routing table, {} connected", self.connected.len()),
+            Err(_) => log::debug!("cannot refresh routing table, no known peers"),
+        }
+    }
+
+    fn next_to_dial(&mut self) -> Option<PeerId> {
+        if self.connected.len() + self.dialing.len() >= self.target {
+            return None;
+        }
+        let (connected, dialing, unreachable) = (&self.connected, &self.dialing, &self.unreachable);
+        self.inner.kademlia.kbuckets().find_map(|bucket| {
+            bucket
+                .iter()
+                .map(|entry| *entry.node.key.preimage())
+                .find(|peer_id| {
+                    !connected.contains(peer_id)
+                        && !dialing.contains(peer_id)
+                        && !unreachable.contains(peer_id)
+                })
+        })
+    }
+}
+
+impl NetworkBehaviour for Discovery {
+    type ConnectionHandler = <Inner as NetworkBehaviour>::ConnectionHandler;
+    type OutEvent = Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match &event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished { peer_id, .. }) => {
+                self.dialing.remove(peer_id);
+                self.connected.insert(*peer_id);
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                remaining_established: 0,
+                ..
+            }) => {
+                self.connected.remove(peer_id);
+            }
+            FromSwarm::DialFailure(DialFailure {
+                peer_id: Some(peer_id),
+                ..
+            }) if self.dialing.remove(peer_id) => {
+                self.unreachable.insert(*peer_id);
+            }
+            _ => {}
+        }
+        self.inner.on_swarm_event(event);
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        let interval = self.interval;
+        let timer = self
+            .timer
+            .get_or_insert_with(|| tokio::time::interval(interval));
+        if timer.poll_tick(cx).is_ready() {
+            self.refresh();
+        }
+
+        while let Poll::Ready(action) = self.inner.poll(cx, params) {
+            match action {
+                ToSwarm::GenerateEvent(event) => self.on_event(event),
+                action => self
+                    .queue
+                    .push_back(action.map_out(|_| unreachable!("event is handled above"))),
+            }
+        }
+
+        if let Some(peer_id) = self.next_to_dial() {
+            log::debug!("dial discovered peer {peer_id}");
+            self.dialing.insert(peer_id);
+            let opts = DialOpts::peer_id(peer_id)
+                .condition(PeerCondition::Disconnected)
+                .build();
+            self.queue.push_back(ToSwarm::Dial { opts });
+        }
+
+        match self.queue.pop_front() {
+            Some(action) => Poll::Ready(action),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        futures::{future, StreamExt},
+        swarm::SwarmEvent,
+        Swarm,
+    };
+
+    use super::*;
+
+    const NODES: usize = 5;
+
+    fn node(seed: Option<&Multiaddr>) -> Swarm<Discovery> {
+        let local_key = crate::generate_identity();
+        let mut discovery =
+            Discovery::new(&local_key, NODES - 1).with_interval(Duration::from_secs(1));
+        if let Some(seed) = seed {
+            assert!(discovery.add_seed(seed));
+        }
+        let listen_on = "/ip4/127.0.0.1/tcp/0".parse::<Multiaddr>().unwrap();
+        crate::swarm(local_key, b"test", [listen_on], [], discovery)
+    }
+
+    #[tokio::test]
+    async fn loopback() {
+        let mut seed = node(None);
+        let seed_addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = seed.select_next_some().await {
+                break address.with(Protocol::P2p((*seed.local_peer_id()).into()));
+            }
+        };
+
+        // the nodes know only the seed, but must find each other
+        let mut swarms = vec![seed];
+        swarms.extend((1..NODES).map(|_| node(Some(&seed_addr))));
+
+        let all_connected = future::poll_fn(|cx| {
+            for swarm in &mut swarms {
+                while let Poll::Ready(Some(_)) = swarm.poll_next_unpin(cx) {}
+            }
+            if swarms
+                .iter()
+                .all(|swarm| swarm.behaviour().connected().count() == NODES - 1)
+            {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(60), all_connected)
+            .await
+            .expect("nodes must discover each other");
+    }
+}
@@ -0,0 +1,527 @@
+//! Fault injection for robustness testing.
+//!
+//! The faults are applied to the stream between noise and yamux, after the remote peer id is
+//! known, so the rules can match both the peer id and the remote address. The stream is
+//! already decrypted by noise, the faults never touch the ciphertext. The effects are:
+//! latency and jitter of the incoming data, bandwidth cap in each direction, stalls that
+//! simulate dropped packets, and connection resets.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use libp2p::{
+    futures::{AsyncRead, AsyncWrite, Future},
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::{Instant, Sleep};
+
+/// Do not read from the socket ahead of the reader more than this.
+const MAX_QUEUED: usize = 1 << 20;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{name} is {value}, must be from 0 to 1")]
+    Probability { name: &'static str, value: f64 },
+    #[error("the bandwidth must not be zero")]
+    ZeroBandwidth,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Faults {
+    /// Delay of the incoming data, milliseconds.
+    pub latency_ms: u64,
+    /// Random extra delay up to this value, milliseconds. The order of bytes is preserved.
+    pub jitter_ms: u64,
+    /// Bytes per second in each direction, unlimited if absent.
+    pub bandwidth: Option<u64>,
+    /// Probability for each chunk of data to stall the connection, simulates a dropped packet.
+    pub stall_probability: f64,
+    /// How long the stall lasts, milliseconds.
+    pub stall_ms: u64,
+    /// Probability for each chunk of data to reset the connection.
+    pub reset_probability: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    /// Prefix of the remote address, `/p2p/...` component matches the peer id.
+    /// For example `/ip4/127.0.0.1`, or `/p2p/12D3KooW...` alone.
+    pub addr: Multiaddr,
+    #[serde(flatten)]
+    pub faults: Faults,
+}
+
+impl Faults {
+    pub fn validate(&self) -> Result<(), Error> {
+        let probabilities = [
+            ("stall_probability", self.stall_probability),
+            ("reset_probability", self.reset_probability),
+        ];
+        for (name, value) in probabilities {
+            // also rejects NaN
+            if !(0.0..=1.0).contains(&value) {
+                return Err(Error::Probability { name, value });
+            }
+        }
+        if self.bandwidth == Some(0) {
+            return Err(Error::ZeroBandwidth);
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn matches(&self, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+        let mut remote = addr.iter().filter(|p| !matches!(p, Protocol::P2p(_)));
+        self.addr.iter().all(|protocol| match protocol {
+            Protocol::P2p(hash) => PeerId::from_multihash(hash).ok() == Some(*peer_id),
+            protocol => remote.next() == Some(protocol),
+        })
+    }
+}
+
+/// The first matching rule applies, otherwise the default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultConfig {
+    pub default: Option<Faults>,
+    pub rules: Vec<Rule>,
+}
+
+impl FaultConfig {
+    /// Read the config from a JSON file and check the values.
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let config = serde_json::from_reader::<_, Self>(File::open(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let rules = self.rules.iter().map(|rule| &rule.faults);
+        self.default
+            .iter()
+            .chain(rules)
+            .try_for_each(Faults::validate)
+    }
+
+    pub fn for_connection(&self, peer_id: &PeerId, addr: &Multiaddr) -> Option<Faults> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(peer_id, addr))
+            .map(|rule| rule.faults.clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            // allow a burst of 100 milliseconds
+            tokens: rate as f64 / 10.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let burst = (self.rate as f64 / 10.0).max(1.0);
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * self.rate as f64).min(burst);
+        self.last = now;
+        self.tokens as usize
+    }
+
+    fn take(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
+
+    fn next_token(&self) -> Instant {
+        self.last + Duration::from_secs_f64(1.0 / self.rate.max(1) as f64)
+    }
+}
+
+/// Applies [`Faults`] to the socket, without faults it only forwards the calls.
+pub(crate) struct Faulty<C> {
+    inner: C,
+    state: Option<Box<State>>,
+}
+
+struct State {
+    faults: Faults,
+    /// The data read from the socket and the time when to deliver it, empty means end of file.
+    incoming: VecDeque<(Instant, Vec<u8>)>,
+    queued: usize,
+    scratch: Box<[u8]>,
+    last_delivery: Instant,
+    stalled_until: Option<Instant>,
+    read_bucket: Option<Bucket>,
+    write_bucket: Option<Bucket>,
+    read_timer: Pin<Box<Sleep>>,
+    write_timer: Pin<Box<Sleep>>,
+}
+
+impl<C> Faulty<C> {
+    pub fn new(inner: C, faults: Option<Faults>) -> Self {
+        let state = faults.map(|faults| {
+            let now = Instant::now();
+            Box::new(State {
+                read_bucket: faults.bandwidth.map(Bucket::new),
+                write_bucket: faults.bandwidth.map(Bucket::new),
+                faults,
+                incoming: VecDeque::default(),
+                queued: 0,
+                scratch: vec![0; 0x10000].into_boxed_slice(),
+                last_delivery: now,
+                stalled_until: None,
+                read_timer: Box::pin(tokio::time::sleep_until(now)),
+                write_timer: Box::pin(tokio::time::sleep_until(now)),
+            })
+        });
+        Faulty { inner, state }
+    }
+}
+
+impl State {
+    /// Decide the fate of a chunk of data, stall or reset the connection.
+    fn roll(&mut self) -> io::Result<()> {
+        let mut rng = rand::thread_rng();
+        if self.faults.reset_probability > 0.0 && rng.gen_bool(self.faults.reset_probability) {
+            log::debug!("inject connection reset");
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        if self.faults.stall_probability > 0.0 && rng.gen_bool(self.faults.stall_probability) {
+            log::debug!("inject stall for {} ms", self.faults.stall_ms);
+            self.stalled_until = Some(Instant::now() + Duration::from_millis(self.faults.stall_ms));
+        }
+        Ok(())
+    }
+
+    fn delivery_time(&mut self) -> Instant {
+        let jitter = match self.faults.jitter_ms {
+            0 => 0,
+            jitter => rand::thread_rng().gen_range(0..=jitter),
+        };
+        let time = Instant::now() + Duration::from_millis(self.faults.latency_ms + jitter);
+        self.last_delivery = self.last_delivery.max(time);
+        self.last_delivery
+    }
+
+    /// Returns `true` if the timer is already expired.
+    fn wait(timer: &mut Pin<Box<Sleep>>, deadline: Instant, cx: &mut Context<'_>) -> bool {
+        timer.as_mut().reset(deadline);
+        timer.as_mut().poll(cx).is_ready()
+    }
+
+    fn stalled(&mut self, write: bool, cx: &mut Context<'_>) -> bool {
+        match self.stalled_until {
+            Some(deadline) if deadline > Instant::now() => {
+                let timer = if write {
+                    &mut self.write_timer
+                } else {
+                    &mut self.read_timer
+                };
+                !Self::wait(timer, deadline, cx)
+            }
+            _ => {
+                self.stalled_until = None;
+                false
+            }
+        }
+    }
+}
+
+impl<C> AsyncRead for Faulty<C>
+where
+    C: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(state) = &mut this.state else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        // read ahead, so the latency does not limit the throughput
+        let mut eof = matches!(state.incoming.back(), Some((_, chunk)) if chunk.is_empty());
+        while !eof && state.queued < MAX_QUEUED {
+            match Pin::new(&mut this.inner).poll_read(cx, &mut state.scratch) {
+                Poll::Ready(Ok(len)) => {
+                    let chunk = state.scratch[..len].to_vec();
+                    eof = len == 0;
+                    if !eof {
+                        state.roll()?;
+                    }
+                    let time = state.delivery_time();
+                    state.queued += len;
+                    state.incoming.push_back((time, chunk));
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            if state.stalled(false, cx) {
+                return Poll::Pending;
+            }
+            let Some((time, chunk)) = state.incoming.front_mut() else {
+                return Poll::Pending;
+            };
+            if *time > Instant::now() && !State::wait(&mut state.read_timer, *time, cx) {
+                return Poll::Pending;
+            }
+            if chunk.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let mut len = buf.len().min(chunk.len());
+            if let Some(bucket) = &mut state.read_bucket {
+                len = len.min(bucket.available());
+                if len == 0 {
+                    let deadline = bucket.next_token();
+                    if State::wait(&mut state.read_timer, deadline, cx) {
+                        continue;
+                    }
+                    return Poll::Pending;
+                }
+                bucket.take(len);
+            }
+            buf[..len].clone_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            if chunk.is_empty() {
+                state.incoming.pop_front();
+            }
+            state.queued -= len;
+            return Poll::Ready(Ok(len));
+        }
+    }
+}
+
+impl<C> AsyncWrite for Faulty<C>
+where
+    C: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(state) = &mut this.state else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        loop {
+            if state.stalled(true, cx) {
+                return Poll::Pending;
+            }
+            let mut len = buf.len();
+            if let Some(bucket) = &mut state.write_bucket {
+                len = len.min(bucket.available());
+                if len == 0 && !buf.is_empty() {
+                    let deadline = bucket.next_token();
+                    if State::wait(&mut state.write_timer, deadline, cx) {
+                        continue;
+                    }
+                    return Poll::Pending;
+                }
+            }
+            let len = match Pin::new(&mut this.inner).poll_write(cx, &buf[..len]) {
+                Poll::Ready(Ok(len)) => len,
+                other => return other,
+            };
+            if let Some(bucket) = &mut state.write_bucket {
+                bucket.take(len);
+            }
+            if len != 0 {
+                state.roll()?;
+            }
+            return Poll::Ready(Ok(len));
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::futures::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use super::*;
+
+    type Pipe = Faulty<Compat<tokio::io::DuplexStream>>;
+
+    /// The faults apply only to the second end.
+    fn pipe(faults: Faults) -> (Pipe, Pipe) {
+        let (a, b) = tokio::io::duplex(0x10000);
+        (
+            Faulty::new(a.compat(), None),
+            Faulty::new(b.compat(), Some(faults)),
+        )
+    }
+
+    #[test]
+    fn rule_matches() {
+        let peer_id = PeerId::random();
+        let other = PeerId::random();
+        let addr = "/ip4/127.0.0.1/tcp/8302".parse::<Multiaddr>().unwrap();
+        let rule = |s: &str| Rule {
+            addr: s.parse().unwrap(),
+            faults: Faults::default(),
+        };
+
+        assert!(rule("/ip4/127.0.0.1").matches(&peer_id, &addr));
+        assert!(!rule("/ip4/127.0.0.2").matches(&peer_id, &addr));
+        assert!(rule(&format!("/p2p/{peer_id}")).matches(&peer_id, &addr));
+        assert!(!rule(&format!("/p2p/{other}")).matches(&peer_id, &addr));
+        assert!(rule(&format!("/ip4/127.0.0.1/tcp/8302/p2p/{peer_id}")).matches(&peer_id, &addr));
+    }
+
+    #[test]
+    fn validate() {
+        let config = |faults: Faults| {
+            FaultConfig {
+                default: Some(Faults {
+                    latency_ms: 10,
+                    ..Default::default()
+                }),
+                rules: vec![Rule {
+                    addr: "/ip4/10.0.0.1".parse().unwrap(),
+                    faults,
+                }],
+            }
+            .validate()
+        };
+
+        config(Faults {
+            reset_probability: 1.0,
+            bandwidth: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let stall = |stall_probability| Faults {
+            stall_probability,
+            ..Default::default()
+        };
+        for p in [1.5, -0.1, f64::NAN] {
+            assert!(matches!(
+                config(stall(p)),
+                Err(Error::Probability {
+                    name: "stall_probability",
+                    ..
+                })
+            ));
+        }
+        assert!(matches!(
+            config(Faults {
+                bandwidth: Some(0),
+                ..Default::default()
+            }),
+            Err(Error::ZeroBandwidth)
+        ));
+    }
+
+    #[tokio::test]
+    async fn latency_and_bandwidth() {
+        let (mut a, mut b) = pipe(Faults {
+            latency_ms: 200,
+            bandwidth: Some(10_000),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+        a.write_all(&[1; 3000]).await.unwrap();
+        a.close().await.unwrap();
+        let mut data = vec![];
+        b.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, [1; 3000]);
+        // 200 ms of latency plus 3000 bytes at 10000 bytes per second minus the burst
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let (mut a, mut b) = pipe(Faults {
+            reset_probability: 1.0,
+            ..Default::default()
+        });
+
+        a.write_all(b"hello").await.unwrap();
+        let err = b.read(&mut [0; 5]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn memory_transport() {
+        use libp2p::{
+            futures::{future, StreamExt},
+            swarm::{dummy, SwarmEvent},
+        };
+
+        let faults = FaultConfig {
+            default: Some(Faults {
+                latency_ms: 50,
+                jitter_ms: 50,
+                ..Default::default()
+            }),
+            rules: vec![],
+        };
+        let addr = format!("/memory/{}", rand::random::<u64>())
+            .parse::<Multiaddr>()
+            .unwrap();
+        let swarm = |listen_on: Vec<Multiaddr>, peers: Vec<Multiaddr>| {
+            crate::Builder::new(crate::generate_identity(), b"test")
+                .memory()
+                .faults(faults.clone())
+                .build(listen_on, peers, dummy::Behaviour)
+        };
+        let mut a = swarm(vec![addr.clone()], vec![]);
+        let mut b = swarm(vec![], vec![addr]);
+
+        async fn connected(swarm: &mut libp2p::Swarm<dummy::Behaviour>) {
+            loop {
+                if let SwarmEvent::ConnectionEstablished { .. } = swarm.select_next_some().await {
+                    break;
+                }
+            }
+        }
+        let both = future::join(connected(&mut a), connected(&mut b));
+        tokio::time::timeout(Duration::from_secs(10), both)
+            .await
+            .expect("must connect over the memory transport");
+    }
+}
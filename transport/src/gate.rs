@@ -0,0 +1,299 @@
+//! Connection gating: peer and address allowlists and denylists, connection limits.
+//!
+//! The rules apply to both inbound and outbound connections. Denied connections are logged
+//! with the reason.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    task::{Context, Poll},
+};
+
+pub use ipnet::IpNet;
+use libp2p::{
+    core::Endpoint,
+    multiaddr::Protocol,
+    swarm::{
+        dummy, ConnectionClosed, ConnectionDenied, ConnectionId, DialFailure, FromSwarm,
+        ListenFailure, NetworkBehaviour, PollParameters, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Default)]
+pub struct GateConfig {
+    /// If not empty, only these peers are allowed.
+    pub allow_peers: BTreeSet<PeerId>,
+    pub deny_peers: BTreeSet<PeerId>,
+    /// If not empty, only addresses in these networks are allowed.
+    pub allow_ips: Vec<IpNet>,
+    pub deny_ips: Vec<IpNet>,
+    pub max_per_ip: Option<usize>,
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Debug, Error)]
+pub enum Denied {
+    #[error("peer {0} is in the denylist")]
+    Peer(PeerId),
+    #[error("peer {0} is not in the allowlist")]
+    PeerNotAllowed(PeerId),
+    #[error("address {0} is in the denylist")]
+    Ip(IpAddr),
+    #[error("address {0} is not in the allowlist")]
+    IpNotAllowed(IpAddr),
+    #[error("too many connections from {0}, the limit is {1}")]
+    PerIpLimit(IpAddr, usize),
+    #[error("too many connections, the limit is {0}")]
+    Limit(usize),
+}
+
+#[derive(Default)]
+pub struct Gate {
+    config: GateConfig,
+    /// Both pending and established connections, and the remote ip if it is known.
+    connections: BTreeMap<ConnectionId, Option<IpAddr>>,
+}
+
+fn ip(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    })
+}
+
+impl Gate {
+    pub fn new(config: GateConfig) -> Self {
+        Gate {
+            config,
+            connections: BTreeMap::default(),
+        }
+    }
+
+    pub fn config(&self) -> &GateConfig {
+        &self.config
+    }
+
+    fn check_peer(&self, peer_id: &PeerId) -> Result<(), Denied> {
+        if self.config.deny_peers.contains(peer_id) {
+            return Err(Denied::Peer(*peer_id));
+        }
+        if !self.config.allow_peers.is_empty() && !self.config.allow_peers.contains(peer_id) {
+            return Err(Denied::PeerNotAllowed(*peer_id));
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), Denied> {
+        if self.config.deny_ips.iter().any(|net| net.contains(&ip)) {
+            return Err(Denied::Ip(ip));
+        }
+        let allow = &self.config.allow_ips;
+        if !allow.is_empty() && !allow.iter().any(|net| net.contains(&ip)) {
+            return Err(Denied::IpNotAllowed(ip));
+        }
+        if let Some(max) = self.config.max_per_ip {
+            let count = self
+                .connections
+                .values()
+                .filter(|other| **other == Some(ip))
+                .count();
+            if count >= max {
+                return Err(Denied::PerIpLimit(ip, max));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_limit(&self) -> Result<(), Denied> {
+        match self.config.max_connections {
+            Some(max) if self.connections.len() >= max => Err(Denied::Limit(max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn deny(&self, addr: Option<&Multiaddr>, reason: Denied) -> ConnectionDenied {
+        match addr {
+            Some(addr) => log::info!("deny connection {addr}, {reason}"),
+            None => log::info!("deny connection, {reason}"),
+        }
+        ConnectionDenied::new(reason)
+    }
+
+    fn established(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let ip = ip(addr);
+        // the ip is already checked if the connection was pending with the known address
+        let ip_checked = matches!(self.connections.get(&connection_id), Some(Some(_)));
+        self.connections.remove(&connection_id);
+        let result = self.check_peer(&peer).and_then(|()| match ip {
+            Some(ip) if !ip_checked => self.check_ip(ip),
+            _ => Ok(()),
+        });
+        match result {
+            Ok(()) => {
+                self.connections.insert(connection_id, ip);
+                Ok(dummy::ConnectionHandler)
+            }
+            Err(reason) => Err(self.deny(Some(addr), reason)),
+        }
+    }
+}
+
+impl NetworkBehaviour for Gate {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type OutEvent = void::Void;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        _local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        let ip = ip(remote_addr);
+        let result = self
+            .check_limit()
+            .and_then(|()| ip.map_or(Ok(()), |ip| self.check_ip(ip)));
+        match result {
+            Ok(()) => {
+                self.connections.insert(connection_id, ip);
+                Ok(())
+            }
+            Err(reason) => Err(self.deny(Some(remote_addr), reason)),
+        }
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        _local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.established(connection_id, peer, remote_addr)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        _addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let result = self.check_limit().and_then(|()| match &maybe_peer {
+            Some(peer_id) => self.check_peer(peer_id),
+            None => Ok(()),
+        });
+        match result {
+            Ok(()) => {
+                self.connections.insert(connection_id, None);
+                Ok(vec![])
+            }
+            Err(reason) => Err(self.deny(None, reason)),
+        }
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.established(connection_id, peer, addr)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionClosed(ConnectionClosed { connection_id, .. })
+            | FromSwarm::DialFailure(DialFailure { connection_id, .. })
+            | FromSwarm::ListenFailure(ListenFailure { connection_id, .. }) => {
+                self.connections.remove(&connection_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let allowed = PeerId::random();
+        let denied = PeerId::random();
+        let mut gate = Gate::new(GateConfig {
+            deny_peers: [denied].into(),
+            allow_ips: vec!["10.0.0.0/8".parse().unwrap()],
+            deny_ips: vec!["10.1.0.0/16".parse().unwrap()],
+            max_per_ip: Some(2),
+            max_connections: Some(3),
+            ..Default::default()
+        });
+        let local = addr("/ip4/10.0.0.1/tcp/8302");
+        let mut id = 0;
+        let mut inbound = |gate: &mut Gate, peer_id: PeerId, remote: &str| {
+            id += 1;
+            let connection_id = ConnectionId::new_unchecked(id);
+            let remote = addr(remote);
+            gate.handle_pending_inbound_connection(connection_id, &local, &remote)
+                .map_err(|err| err.downcast::<Denied>().unwrap())?;
+            gate.handle_established_inbound_connection(connection_id, peer_id, &local, &remote)
+                .map(drop)
+                .map_err(|err| err.downcast::<Denied>().unwrap())
+        };
+
+        assert!(matches!(
+            inbound(&mut gate, allowed, "/ip4/192.168.0.1/tcp/1"),
+            Err(Denied::IpNotAllowed(_))
+        ));
+        assert!(matches!(
+            inbound(&mut gate, allowed, "/ip4/10.1.0.1/tcp/1"),
+            Err(Denied::Ip(_))
+        ));
+        assert!(matches!(
+            inbound(&mut gate, denied, "/ip4/10.0.0.2/tcp/1"),
+            Err(Denied::Peer(_))
+        ));
+        assert!(inbound(&mut gate, allowed, "/ip4/10.0.0.2/tcp/1").is_ok());
+        assert!(inbound(&mut gate, allowed, "/ip4/10.0.0.2/tcp/2").is_ok());
+        assert!(matches!(
+            inbound(&mut gate, allowed, "/ip4/10.0.0.2/tcp/3"),
+            Err(Denied::PerIpLimit(_, 2))
+        ));
+        assert!(inbound(&mut gate, allowed, "/ip4/10.0.0.3/tcp/1").is_ok());
+        assert!(matches!(
+            inbound(&mut gate, allowed, "/ip4/10.0.0.4/tcp/1"),
+            Err(Denied::Limit(3))
+        ));
+    }
+}
@@ -0,0 +1,286 @@
+//! Load and store the libp2p identity in the formats used by Mina.
+//!
+//! The file format is the same as `mina libp2p generate-keypair` writes: a JSON secret box
+//! (argon2i for the password, xsalsa20poly1305 for the payload). The payload is a string
+//! `secret,public,peer_id`, where the keys are base64 encoded protobuf structures.
+//! The password is taken from the `MINA_LIBP2P_PASS` environment variable.
+//!
+//! The key can also be given as a base58check string with version byte `0x80`,
+//! this is what `OPENMINA_P2P_SEC_KEY` environment variable contains.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use crypto_secretbox::{
+    aead::{Aead, KeyInit},
+    XSalsa20Poly1305,
+};
+use libp2p::identity::{ed25519, DecodingError, Keypair};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const PASSWORD_ENV: &str = "MINA_LIBP2P_PASS";
+pub const SECRET_KEY_ENV: &str = "OPENMINA_P2P_SEC_KEY";
+
+const BOX_PRIMITIVE: &str = "xsalsa20poly1305";
+const PW_PRIMITIVE: &str = "argon2i";
+/// Version byte of the base58check strings inside the secret box.
+const SECRET_BOX_VERSION: u8 = 0x02;
+const BS58_VERSION: u8 = 0x80;
+
+/// Memory limit (bytes) and number of passes, same as libsodium `crypto_pwhash` moderate.
+const PW_DIFF: (u64, u32) = (134217728, 6);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Base58(#[from] bs58::decode::Error),
+    #[error("{0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("{0}")]
+    Decoding(#[from] DecodingError),
+    #[error("unsupported primitive {0}")]
+    UnsupportedPrimitive(String),
+    #[error("bad password difficulty, {0}")]
+    Difficulty(argon2::Error),
+    #[error("cannot decrypt the key, wrong password")]
+    Decrypt,
+    #[error("malformed key file")]
+    Malformed,
+    #[error("only ed25519 keys are supported")]
+    NotEd25519,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SecretBox {
+    box_primitive: String,
+    pw_primitive: String,
+    nonce: String,
+    pwsalt: String,
+    pwdiff: (u64, u32),
+    ciphertext: String,
+}
+
+impl SecretBox {
+    fn key(
+        password: &[u8],
+        salt: &[u8],
+        (mem_limit, ops_limit): (u64, u32),
+    ) -> Result<[u8; 32], Error> {
+        use argon2::{Algorithm, Argon2, Params, Version};
+
+        let params = Params::new((mem_limit / 1024) as u32, ops_limit, 1, Some(32))
+            .map_err(Error::Difficulty)?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2i, Version::V0x13, params)
+            .hash_password_into(password, salt, &mut key)
+            .map_err(Error::Difficulty)?;
+        Ok(key)
+    }
+
+    fn encrypt(plaintext: &[u8], password: &[u8], pwdiff: (u64, u32)) -> Result<Self, Error> {
+        let nonce = rand::random::<[u8; 24]>();
+        let pwsalt = rand::random::<[u8; 16]>();
+        let key = Self::key(password, &pwsalt, pwdiff)?;
+        let ciphertext = XSalsa20Poly1305::new(&key.into())
+            .encrypt(&nonce.into(), plaintext)
+            .map_err(|_| Error::Decrypt)?;
+        let encode = |bytes: &[u8]| {
+            bs58::encode(bytes)
+                .with_check_version(SECRET_BOX_VERSION)
+                .into_string()
+        };
+
+        Ok(SecretBox {
+            box_primitive: BOX_PRIMITIVE.to_owned(),
+            pw_primitive: PW_PRIMITIVE.to_owned(),
+            nonce: encode(&nonce),
+            pwsalt: encode(&pwsalt),
+            pwdiff,
+            ciphertext: encode(&ciphertext),
+        })
+    }
+
+    fn decrypt(&self, password: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.box_primitive != BOX_PRIMITIVE {
+            return Err(Error::UnsupportedPrimitive(self.box_primitive.clone()));
+        }
+        if self.pw_primitive != PW_PRIMITIVE {
+            return Err(Error::UnsupportedPrimitive(self.pw_primitive.clone()));
+        }
+        let decode = |s: &str| -> Result<Vec<u8>, Error> {
+            let mut bytes = bs58::decode(s)
+                .with_check(Some(SECRET_BOX_VERSION))
+                .into_vec()?;
+            bytes.remove(0);
+            Ok(bytes)
+        };
+        let nonce = <[u8; 24]>::try_from(decode(&self.nonce)?).map_err(|_| Error::Malformed)?;
+        let key = Self::key(password, &decode(&self.pwsalt)?, self.pwdiff)?;
+        XSalsa20Poly1305::new(&key.into())
+            .decrypt(&nonce.into(), decode(&self.ciphertext)?.as_slice())
+            .map_err(|_| Error::Decrypt)
+    }
+}
+
+fn password() -> Vec<u8> {
+    match env::var(PASSWORD_ENV) {
+        Ok(password) => password.into_bytes(),
+        Err(_) => {
+            log::warn!("{PASSWORD_ENV} is not set, the key file uses an empty password");
+            vec![]
+        }
+    }
+}
+
+/// Read the key file written by `mina libp2p generate-keypair`,
+/// the password is taken from `MINA_LIBP2P_PASS`.
+pub fn load<P>(path: P) -> Result<Keypair, Error>
+where
+    P: AsRef<Path>,
+{
+    load_with_password(path, &password())
+}
+
+pub fn load_with_password<P>(path: P, password: &[u8]) -> Result<Keypair, Error>
+where
+    P: AsRef<Path>,
+{
+    let secret_box = serde_json::from_slice::<SecretBox>(&fs::read(path)?)?;
+    let plaintext =
+        String::from_utf8(secret_box.decrypt(password)?).map_err(|_| Error::Malformed)?;
+    let secret = plaintext.split(',').next().ok_or(Error::Malformed)?;
+    Ok(Keypair::from_protobuf_encoding(&BASE64.decode(secret)?)?)
+}
+
+/// Write the key file in the same format as `mina libp2p generate-keypair` does,
+/// the password is taken from `MINA_LIBP2P_PASS`. Also writes the peer id into `<path>.peerid`.
+pub fn save<P>(path: P, keypair: &Keypair) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    save_with_password(path, keypair, &password())
+}
+
+pub fn save_with_password<P>(path: P, keypair: &Keypair, password: &[u8]) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    save_inner(path.as_ref(), keypair, password, PW_DIFF)
+}
+
+fn save_inner(
+    path: &Path,
+    keypair: &Keypair,
+    password: &[u8],
+    pwdiff: (u64, u32),
+) -> Result<(), Error> {
+    let peer_id = keypair.public().to_peer_id().to_base58();
+    let plaintext = format!(
+        "{},{},{peer_id}",
+        BASE64.encode(keypair.to_protobuf_encoding()?),
+        BASE64.encode(keypair.public().to_protobuf_encoding()),
+    );
+    let secret_box = SecretBox::encrypt(plaintext.as_bytes(), password, pwdiff)?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)?
+        .write_all(&serde_json::to_vec(&secret_box)?)?;
+
+    let mut peer_id_path = path.as_os_str().to_owned();
+    peer_id_path.push(".peerid");
+    fs::write(peer_id_path, peer_id)?;
+
+    Ok(())
+}
+
+/// Parse the secret key given as base58check string.
+pub fn from_bs58(s: &str) -> Result<Keypair, Error> {
+    let mut bytes = bs58::decode(s.trim())
+        .with_check(Some(BS58_VERSION))
+        .into_vec()?;
+    let secret = ed25519::SecretKey::from_bytes(&mut bytes[1..])?;
+    Ok(ed25519::Keypair::from(secret).into())
+}
+
+pub fn to_bs58(keypair: &Keypair) -> Result<String, Error> {
+    let keypair = keypair.clone().into_ed25519().ok_or(Error::NotEd25519)?;
+    Ok(bs58::encode(keypair.secret())
+        .with_check_version(BS58_VERSION)
+        .into_string())
+}
+
+/// Take the key from `OPENMINA_P2P_SEC_KEY` if it is set, otherwise read the key file.
+/// If there is no key file, generate a new key and store it in the file.
+/// Without a path the new key is not stored anywhere.
+pub fn load_or_generate(path: Option<&Path>) -> Result<Keypair, Error> {
+    if let Ok(s) = env::var(SECRET_KEY_ENV) {
+        return from_bs58(&s);
+    }
+    match path {
+        Some(path) if path.exists() => load(path),
+        Some(path) => {
+            let keypair = super::generate_identity();
+            save(path, &keypair)?;
+            log::info!("generated new key, stored in {}", path.display());
+            Ok(keypair)
+        }
+        None => {
+            log::info!("generated new key, it will not be stored");
+            Ok(super::generate_identity())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_round_trip() {
+        let path = env::temp_dir().join(format!("mina-key-{}", std::process::id()));
+        let keypair = crate::generate_identity();
+        // cheap difficulty to keep the test fast
+        save_inner(&path, &keypair, b"password", (1024 * 1024, 1)).unwrap();
+
+        let loaded = load_with_password(&path, b"password").unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+        assert!(matches!(
+            load_with_password(&path, b"wrong"),
+            Err(Error::Decrypt)
+        ));
+
+        let mut peer_id_path = path.as_os_str().to_owned();
+        peer_id_path.push(".peerid");
+        let peer_id = fs::read_to_string(&peer_id_path).unwrap();
+        assert_eq!(peer_id, keypair.public().to_peer_id().to_base58());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(peer_id_path).unwrap();
+    }
+
+    #[test]
+    fn bs58_round_trip() {
+        let keypair = crate::generate_identity();
+        let s = to_bs58(&keypair).unwrap();
+        assert_eq!(from_bs58(&s).unwrap().public(), keypair.public());
+    }
+}
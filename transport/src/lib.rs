@@ -1,14 +1,31 @@
 #![forbid(unsafe_code)]
 
+pub mod capture;
+pub mod keys;
+pub mod discovery;
+pub mod chain_id;
+pub mod faults;
+pub mod gate;
+pub mod address_book;
+pub mod network;
+
 use libp2p::Swarm;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::{tcp, noise, pnet, yamux, core::upgrade, Transport, dns};
+use libp2p::core::transport::MemoryTransport;
 use libp2p::{
     swarm::SwarmBuilder,
     futures::{AsyncRead, AsyncWrite},
     identity, PeerId, Multiaddr,
 };
 pub use libp2p::identity::{ed25519, Keypair};
+use either::Either;
+
+use self::address_book::AddressBook;
+use self::capture::{Capture, Recorder};
+use self::discovery::Discovery;
+use self::faults::{FaultConfig, Faulty};
+use self::gate::Gate;
 
 pub use libp2p::futures;
 
@@ -18,6 +35,45 @@ pub fn generate_identity() -> Keypair {
     identity::Keypair::generate_ed25519()
 }
 
+/// The behaviour of the application combined with the connection gating, the address book
+/// and the peer discovery.
+#[derive(NetworkBehaviour)]
+pub struct Behaviour<B>
+where
+    B: NetworkBehaviour,
+{
+    pub gate: Gate,
+    pub address_book: AddressBook,
+    pub discovery: Discovery,
+    pub inner: B,
+}
+
+impl<B> Behaviour<B>
+where
+    B: NetworkBehaviour,
+{
+    /// Accepts every connection, use [`Behaviour::with_gate`] to restrict.
+    /// The address book is kept in memory, use [`Behaviour::with_address_book`] to store it.
+    pub fn new(discovery: Discovery, inner: B) -> Self {
+        Behaviour {
+            gate: Gate::default(),
+            address_book: AddressBook::default(),
+            discovery,
+            inner,
+        }
+    }
+
+    pub fn with_gate(mut self, gate: Gate) -> Self {
+        self.gate = gate;
+        self
+    }
+
+    pub fn with_address_book(mut self, address_book: AddressBook) -> Self {
+        self.address_book = address_book;
+        self
+    }
+}
+
 /// Create and configure a libp2p swarm. This will be able to talk to the Mina node.
 pub fn swarm<B, I, J>(
     local_key: Keypair,
@@ -31,6 +87,71 @@ where
     I: IntoIterator<Item = Multiaddr>,
     J: IntoIterator<Item = Multiaddr>,
 {
+    Builder::new(local_key, chain_id).build(listen_on, peers, behaviour)
+}
+
+/// Same as [`swarm`], but allows to configure optional features of the transport.
+pub struct Builder<'a> {
+    local_key: Keypair,
+    chain_id: &'a [u8],
+    capture: Option<Capture>,
+    faults: Option<FaultConfig>,
+    memory: bool,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(local_key: Keypair, chain_id: &'a [u8]) -> Self {
+        Builder {
+            local_key,
+            chain_id,
+            capture: None,
+            faults: None,
+            memory: false,
+        }
+    }
+
+    /// Write the decrypted traffic of every connection into the capture.
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Degrade the connections, see [`faults`].
+    pub fn faults(mut self, faults: FaultConfig) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Use the in-memory transport instead of TCP, the addresses look like `/memory/1234`.
+    pub fn memory(mut self) -> Self {
+        self.memory = true;
+        self
+    }
+
+    pub fn build<B, I, J>(self, listen_on: J, peers: I, behaviour: B) -> Swarm<B>
+    where
+        B: NetworkBehaviour,
+        I: IntoIterator<Item = Multiaddr>,
+        J: IntoIterator<Item = Multiaddr>,
+    {
+        build(self, listen_on, peers, behaviour)
+    }
+}
+
+fn build<B, I, J>(builder: Builder, listen_on: J, peers: I, behaviour: B) -> Swarm<B>
+where
+    B: NetworkBehaviour,
+    I: IntoIterator<Item = Multiaddr>,
+    J: IntoIterator<Item = Multiaddr>,
+{
+    let Builder {
+        local_key,
+        chain_id,
+        capture,
+        faults,
+        memory,
+    } = builder;
+
     let local_peer_id = PeerId::from(local_key.public());
 
     let pnet = {
@@ -57,15 +178,33 @@ where
             task::{self, Context, Poll},
             io,
         };
-        use libp2p::core::{UpgradeInfo, InboundUpgrade, OutboundUpgrade};
+        use libp2p::core::{UpgradeInfo, InboundUpgrade, OutboundUpgrade, ConnectedPoint};
 
         #[derive(Clone)]
-        struct CodaYamux(yamux::YamuxConfig);
+        struct CodaYamux(
+            yamux::YamuxConfig,
+            Option<(Capture, PeerId, ConnectedPoint)>,
+            Option<faults::Faults>,
+        );
+
+        impl CodaYamux {
+            fn wrap<C>(&self, socket: C) -> SocketWrapper<Faulty<C>> {
+                let recorder = self
+                    .1
+                    .as_ref()
+                    .and_then(|(capture, peer_id, endpoint)| capture.connection(peer_id, endpoint));
+                SocketWrapper {
+                    inner: Faulty::new(socket, self.2.clone()),
+                    recorder,
+                }
+            }
+        }
 
         pin_project_lite::pin_project! {
             struct SocketWrapper<C> {
                 #[pin]
                 inner: C,
+                recorder: Option<Recorder>,
             }
         }
 
@@ -81,7 +220,10 @@ where
                 let this = self.project();
                 let len = task::ready!(this.inner.poll_write(cx, buf))?;
                 if len != 0 {
-                    log::debug!("<- {}", hex::encode(&buf[..len]));
+                    log::trace!("<- {}", hex::encode(&buf[..len]));
+                    if let Some(recorder) = this.recorder {
+                        recorder.on_write(&buf[..len]);
+                    }
                 }
 
                 Poll::Ready(Ok(len))
@@ -94,6 +236,9 @@ where
 
             fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
                 let this = self.project();
+                if let Some(recorder) = this.recorder {
+                    recorder.flush();
+                }
                 this.inner.poll_close(cx)
             }
         }
@@ -110,7 +255,10 @@ where
                 let this = self.project();
                 let len = task::ready!(this.inner.poll_read(cx, buf))?;
                 if len != 0 {
-                    log::debug!("-> {}", hex::encode(&buf[..len]));
+                    log::trace!("-> {}", hex::encode(&buf[..len]));
+                    if let Some(recorder) = this.recorder {
+                        recorder.on_read(&buf[..len]);
+                    }
                 }
 
                 Poll::Ready(Ok(len))
@@ -130,13 +278,13 @@ where
         where
             C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
         {
-            type Output = <yamux::YamuxConfig as InboundUpgrade<SocketWrapper<C>>>::Output;
+            type Output = <yamux::YamuxConfig as InboundUpgrade<SocketWrapper<Faulty<C>>>>::Output;
             type Error = <yamux::YamuxConfig as InboundUpgrade<C>>::Error;
-            type Future = <yamux::YamuxConfig as InboundUpgrade<SocketWrapper<C>>>::Future;
+            type Future = <yamux::YamuxConfig as InboundUpgrade<SocketWrapper<Faulty<C>>>>::Future;
 
             fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
-                self.0
-                    .upgrade_inbound(SocketWrapper { inner: socket }, info)
+                let socket = self.wrap(socket);
+                self.0.upgrade_inbound(socket, info)
             }
         }
 
@@ -144,23 +292,38 @@ where
         where
             C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
         {
-            type Output = <yamux::YamuxConfig as OutboundUpgrade<SocketWrapper<C>>>::Output;
+            type Output = <yamux::YamuxConfig as OutboundUpgrade<SocketWrapper<Faulty<C>>>>::Output;
             type Error = <yamux::YamuxConfig as OutboundUpgrade<C>>::Error;
-            type Future = <yamux::YamuxConfig as OutboundUpgrade<SocketWrapper<C>>>::Future;
+            type Future = <yamux::YamuxConfig as OutboundUpgrade<SocketWrapper<Faulty<C>>>>::Future;
 
             fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
-                self.0
-                    .upgrade_outbound(SocketWrapper { inner: socket }, info)
+                let socket = self.wrap(socket);
+                self.0.upgrade_outbound(socket, info)
             }
         }
 
-        CodaYamux(yamux::YamuxConfig::default())
+        move |peer_id: &PeerId, endpoint: &ConnectedPoint| {
+            let capture = capture
+                .clone()
+                .map(|capture| (capture, *peer_id, endpoint.clone()));
+            let faults = faults
+                .as_ref()
+                .and_then(|faults| faults.for_connection(peer_id, endpoint.get_remote_address()));
+            CodaYamux(yamux::YamuxConfig::default(), capture, faults)
+        }
+    };
+    let transport = if memory {
+        Either::Left(MemoryTransport::default())
+    } else {
+        Either::Right(tcp::tokio::Transport::new(
+            tcp::Config::default().nodelay(true),
+        ))
     };
-    let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+    let transport = transport
         .and_then(move |socket, _| pnet.handshake(socket))
         .upgrade(upgrade::Version::V1)
         .authenticate(noise)
-        .multiplex(yamux)
+        .multiplex_ext(yamux)
         .timeout(std::time::Duration::from_secs(20))
         .boxed();
     let transport = dns::TokioDnsConfig::system(transport).unwrap().boxed();
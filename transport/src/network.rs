@@ -0,0 +1,518 @@
+//! Parameters of a Mina network: chain id, seed peers, gossip topic and the constants.
+//!
+//! Select a preset by name with [`Network::select`], or describe a custom network,
+//! for example a local one, in a TOML or JSON file with the same fields as [`Network`].
+//! The constants of a network are overridden by the runtime config of the Mina node
+//! with [`Network::with_runtime_config`].
+
+use std::{fs, io, path::Path};
+
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::chain_id::{ChainIdInputs, GenesisConstants};
+
+pub const GOSSIP_TOPIC: &str = "coda/consensus-messages/0.0.1";
+
+pub const PRESETS: [&str; 3] = ["mainnet", "devnet", "berkeley"];
+
+/// `f` of the consensus, the fraction of the slots that have a block, the same on
+/// all the presets.
+pub const ACTIVE_SLOTS_COEFFICIENT: f64 = 0.75;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("runtime config: {0}")]
+    RuntimeConfig(String),
+    #[error("fork state hash: {0}")]
+    ForkStateHash(String),
+}
+
+/// The chain is a continuation of another chain after a hard fork, the new genesis
+/// block follows the block with this state hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForkConfig {
+    /// Base58check string, like `3NK...`.
+    pub previous_state_hash: String,
+    pub previous_length: u32,
+    pub previous_global_slot: u32,
+}
+
+/// The values have the same meaning as in the runtime config of the Mina node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConstraintConstants {
+    pub sub_windows_per_window: u64,
+    pub ledger_depth: u64,
+    pub work_delay: u64,
+    pub block_window_duration_ms: u64,
+    pub transaction_capacity_log_2: u64,
+    pub pending_coinbase_depth: usize,
+    /// Nanomina.
+    pub coinbase_amount: u64,
+    pub supercharged_coinbase_factor: u64,
+    /// Nanomina.
+    pub account_creation_fee: u64,
+    #[serde(default)]
+    pub fork: Option<ForkConfig>,
+}
+
+pub const BERKELEY_CONSTRAINT_CONSTANTS: ConstraintConstants = ConstraintConstants {
+    sub_windows_per_window: 11,
+    ledger_depth: 35,
+    work_delay: 2,
+    block_window_duration_ms: 180000,
+    transaction_capacity_log_2: 7,
+    pending_coinbase_depth: 5,
+    coinbase_amount: 720000000000,
+    supercharged_coinbase_factor: 2,
+    account_creation_fee: 1000000000,
+    fork: None,
+};
+
+/// The same as on Berkeley since the hard fork, the ledger depth is 35 on all the presets.
+pub const MAINNET_CONSTRAINT_CONSTANTS: ConstraintConstants = BERKELEY_CONSTRAINT_CONSTANTS;
+
+/// Since the Berkeley hard fork, 2024-06-05.
+pub const MAINNET_GENESIS_CONSTANTS: GenesisConstants = GenesisConstants {
+    k: 290,
+    slots_per_epoch: 7140,
+    slots_per_sub_window: 7,
+    delta: 0,
+    txpool_max_size: 3000,
+    // 2024-06-05T00:00:00Z
+    genesis_state_timestamp: 1717545600000,
+};
+
+/// What `mina advanced chain-id-inputs` prints on the mainnet since the Berkeley hard fork.
+pub fn mainnet_chain_id_inputs() -> ChainIdInputs {
+    ChainIdInputs {
+        genesis_state_hash: "3NK4BpDSekaqsG6tx8Nse2zJchRft2JpnbvMiog55WCr5xJZaKeP".to_owned(),
+        genesis_constants: MAINNET_GENESIS_CONSTANTS,
+        // transaction-merge, transaction-base, blockchain-step
+        constraint_system_digests: vec![
+            "b8879f677f622a1d86648030701f43e1".to_owned(),
+            "d31948e661cc662675b0c079458f714a".to_owned(),
+            "14ab5562ed292de7a3deb9e12f00aec0".to_owned(),
+        ],
+        protocol_transaction_version: 3,
+        protocol_network_version: 3,
+    }
+}
+
+#[cfg(feature = "ledger")]
+impl ConstraintConstants {
+    pub fn to_ledger(
+        &self,
+    ) -> Result<mina_tree::scan_state::scan_state::ConstraintConstants, Error> {
+        use mina_p2p_messages::v2;
+        use mina_tree::scan_state::{
+            currency::{Amount, Fee, Length, Slot},
+            scan_state::ForkConstants,
+        };
+
+        let fork = match &self.fork {
+            Some(fork) => {
+                let hash = fork
+                    .previous_state_hash
+                    .parse::<v2::StateHash>()
+                    .map_err(|err| Error::ForkStateHash(format!("{err:?}")))?;
+                let previous_state_hash = hash
+                    .to_fp()
+                    .map_err(|err| Error::ForkStateHash(format!("{err:?}")))?;
+                Some(ForkConstants {
+                    previous_state_hash,
+                    previous_length: Length::from_u32(fork.previous_length),
+                    previous_global_slot: Slot::from_u32(fork.previous_global_slot),
+                })
+            }
+            None => None,
+        };
+
+        Ok(mina_tree::scan_state::scan_state::ConstraintConstants {
+            sub_windows_per_window: self.sub_windows_per_window,
+            ledger_depth: self.ledger_depth,
+            work_delay: self.work_delay,
+            block_window_duration_ms: self.block_window_duration_ms,
+            transaction_capacity_log_2: self.transaction_capacity_log_2,
+            pending_coinbase_depth: self.pending_coinbase_depth,
+            coinbase_amount: Amount::from_u64(self.coinbase_amount),
+            supercharged_coinbase_factor: self.supercharged_coinbase_factor,
+            account_creation_fee: Fee::from_u64(self.account_creation_fee),
+            fork,
+        })
+    }
+}
+
+/// The sections of the runtime config of the Mina node that have the constants,
+/// every value is optional, as in the node.
+#[derive(Deserialize)]
+struct RuntimeConfig {
+    proof: Option<ProofKeys>,
+    genesis: Option<GenesisKeys>,
+    daemon: Option<DaemonKeys>,
+}
+
+#[derive(Deserialize)]
+struct ProofKeys {
+    sub_windows_per_window: Option<u64>,
+    ledger_depth: Option<u64>,
+    work_delay: Option<u64>,
+    block_window_duration_ms: Option<u64>,
+    transaction_capacity: Option<TransactionCapacity>,
+    /// Mina, like `"720"` or `"0.5"`.
+    coinbase_amount: Option<String>,
+    supercharged_coinbase_factor: Option<u64>,
+    account_creation_fee: Option<String>,
+    fork: Option<ForkConfig>,
+}
+
+#[derive(Deserialize)]
+enum TransactionCapacity {
+    #[serde(rename = "2_to_the")]
+    Log2(u64),
+    #[serde(rename = "txns_per_second_x10")]
+    TxnsPerSecondX10(u64),
+}
+
+#[derive(Deserialize)]
+struct GenesisKeys {
+    k: Option<u32>,
+    delta: Option<u32>,
+    slots_per_epoch: Option<u32>,
+    slots_per_sub_window: Option<u32>,
+    /// Like `"2024-02-02T14:01:01Z"`.
+    genesis_state_timestamp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DaemonKeys {
+    txpool_max_size: Option<u32>,
+}
+
+/// Nanomina from a decimal string of mina.
+fn parse_mina(s: &str) -> Result<u64, Error> {
+    let invalid = || Error::RuntimeConfig(format!("invalid amount {s}"));
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let whole = whole.parse::<u64>().map_err(|_| invalid())?;
+    let fraction = format!("{fraction:0<9}")
+        .parse::<u64>()
+        .map_err(|_| invalid())?;
+    whole
+        .checked_mul(1_000_000_000)
+        .and_then(|x| x.checked_add(fraction))
+        .ok_or_else(invalid)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Network {
+    pub name: String,
+    /// Use its bytes as `chain_id` for [`crate::swarm`].
+    pub chain_id: String,
+    #[serde(default)]
+    pub seeds: Vec<Multiaddr>,
+    #[serde(default = "default_gossip_topic")]
+    pub gossip_topic: String,
+    #[serde(default = "default_active_slots_coefficient")]
+    pub active_slots_coefficient: f64,
+    pub constraint_constants: ConstraintConstants,
+    pub genesis_constants: GenesisConstants,
+}
+
+fn default_gossip_topic() -> String {
+    GOSSIP_TOPIC.to_owned()
+}
+
+fn default_active_slots_coefficient() -> f64 {
+    ACTIVE_SLOTS_COEFFICIENT
+}
+
+fn parse_seeds(seeds: &[&str]) -> Vec<Multiaddr> {
+    seeds
+        .iter()
+        .map(|s| s.parse().expect("valid constant"))
+        .collect()
+}
+
+impl Network {
+    /// The network since the Berkeley hard fork, the chain id is derived from the published
+    /// inputs. The seeds are not listed, the Mina node takes them from a list published
+    /// separately, pass them with `--peer`.
+    pub fn mainnet() -> Self {
+        Network {
+            name: "mainnet".to_owned(),
+            chain_id: mainnet_chain_id_inputs().chain_id(),
+            seeds: vec![],
+            gossip_topic: default_gossip_topic(),
+            active_slots_coefficient: ACTIVE_SLOTS_COEFFICIENT,
+            constraint_constants: ConstraintConstants {
+                fork: Some(ForkConfig {
+                    previous_state_hash: "3NLRTfY4kZyJtvaP4dFenDcxfoMfT3uEpkWS913KkeXLtziyVd15"
+                        .to_owned(),
+                    previous_length: 359604,
+                    previous_global_slot: 564480,
+                }),
+                ..MAINNET_CONSTRAINT_CONSTANTS
+            },
+            genesis_constants: MAINNET_GENESIS_CONSTANTS,
+        }
+    }
+
+    /// The network since the Berkeley hard fork, the chain id is the published one,
+    /// the constraint system digests it is derived from are not listed here.
+    pub fn devnet() -> Self {
+        Network {
+            name: "devnet".to_owned(),
+            chain_id: "29936104443aaf264a7f0192ac64b1c7173198c1ed404c1bcff5e562e05eb7f6".to_owned(),
+            seeds: parse_seeds(&[
+                "/dns4/seed-1.devnet.gcp.o1test.net/tcp/10003/p2p/12D3KooWAdgYL6hv18M3iDBdaK1dRygPivSfAfBNDzie6YqydVbs",
+                "/dns4/seed-2.devnet.gcp.o1test.net/tcp/10003/p2p/12D3KooWLjs54xHzVmMmGYb7W5RVibqbwD1co7M2ZMfPgPm7iAag",
+                "/dns4/seed-3.devnet.gcp.o1test.net/tcp/10003/p2p/12D3KooWEiGVAFC7curXWXiGZyMWnZK9h8BKr88U8D5PKV3dXciv",
+            ]),
+            gossip_topic: default_gossip_topic(),
+            active_slots_coefficient: ACTIVE_SLOTS_COEFFICIENT,
+            constraint_constants: ConstraintConstants {
+                fork: Some(ForkConfig {
+                    previous_state_hash: "3NKSiqFZQmAS12U8qeX4KNo8b4199spwNh7mrSs4Ci1Vacpfix2Q"
+                        .to_owned(),
+                    previous_length: 296371,
+                    previous_global_slot: 445860,
+                }),
+                ..BERKELEY_CONSTRAINT_CONSTANTS
+            },
+            genesis_constants: GenesisConstants {
+                k: 290,
+                slots_per_epoch: 7140,
+                slots_per_sub_window: 7,
+                delta: 0,
+                txpool_max_size: 3000,
+                // 2024-04-09T21:00:00Z
+                genesis_state_timestamp: 1712696400000,
+            },
+        }
+    }
+
+    /// The testnet that started on Berkeley, it is not a continuation of another chain.
+    /// The chain id is the published one.
+    pub fn berkeley() -> Self {
+        Network {
+            name: "berkeley".to_owned(),
+            chain_id: "667b328bfc09ced12191d099f234575b006b6b193f5441a6fa744feacd9744db".to_owned(),
+            seeds: parse_seeds(&[
+                "/dns4/seed-1.berkeley.o1test.net/tcp/10000/p2p/12D3KooWAdgYL6hv18M3iDBdaK1dRygPivSfAfBNDzie6YqydVbs",
+                "/dns4/seed-2.berkeley.o1test.net/tcp/10001/p2p/12D3KooWLjs54xHzVmMmGYb7W5RVibqbwD1co7M2ZMfPgPm7iAag",
+                "/dns4/seed-3.berkeley.o1test.net/tcp/10002/p2p/12D3KooWEiGVAFC7curXWXiGZyMWnZK9h8BKr88U8D5PKV3dXciv",
+            ]),
+            gossip_topic: default_gossip_topic(),
+            active_slots_coefficient: ACTIVE_SLOTS_COEFFICIENT,
+            constraint_constants: BERKELEY_CONSTRAINT_CONSTANTS,
+            genesis_constants: GenesisConstants {
+                k: 290,
+                slots_per_epoch: 7140,
+                slots_per_sub_window: 7,
+                delta: 0,
+                txpool_max_size: 3000,
+                // 2024-02-02T14:01:01Z
+                genesis_state_timestamp: 1706882461000,
+            },
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Self::mainnet()),
+            "devnet" => Some(Self::devnet()),
+            "berkeley" => Some(Self::berkeley()),
+            _ => None,
+        }
+    }
+
+    /// Read the network from a file, TOML if the extension is `.toml`, JSON otherwise.
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            Ok(toml::from_str(&text)?)
+        } else {
+            Ok(serde_json::from_str(&text)?)
+        }
+    }
+
+    /// Override the constants with the ones in the runtime config of the Mina node at
+    /// this path, the `proof`, `genesis` and `daemon` sections, the absent values stay.
+    /// The chain id stays, it depends on more than the constants.
+    pub fn with_runtime_config<P>(mut self, path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let text = fs::read_to_string(path)?;
+        let config = serde_json::from_str::<RuntimeConfig>(&text)?;
+
+        if let Some(proof) = config.proof {
+            let c = &mut self.constraint_constants;
+            c.sub_windows_per_window = proof
+                .sub_windows_per_window
+                .unwrap_or(c.sub_windows_per_window);
+            c.ledger_depth = proof.ledger_depth.unwrap_or(c.ledger_depth);
+            c.work_delay = proof.work_delay.unwrap_or(c.work_delay);
+            c.block_window_duration_ms = proof
+                .block_window_duration_ms
+                .unwrap_or(c.block_window_duration_ms);
+            match proof.transaction_capacity {
+                Some(TransactionCapacity::Log2(log_2)) => c.transaction_capacity_log_2 = log_2,
+                Some(TransactionCapacity::TxnsPerSecondX10(_)) => {
+                    return Err(Error::RuntimeConfig(
+                        "transaction capacity must be given as `2_to_the`".to_owned(),
+                    ));
+                }
+                None => {}
+            }
+            if let Some(amount) = &proof.coinbase_amount {
+                c.coinbase_amount = parse_mina(amount)?;
+            }
+            c.supercharged_coinbase_factor = proof
+                .supercharged_coinbase_factor
+                .unwrap_or(c.supercharged_coinbase_factor);
+            if let Some(fee) = &proof.account_creation_fee {
+                c.account_creation_fee = parse_mina(fee)?;
+            }
+            if proof.fork.is_some() {
+                c.fork = proof.fork;
+            }
+        }
+        if let Some(genesis) = config.genesis {
+            let g = &mut self.genesis_constants;
+            g.k = genesis.k.unwrap_or(g.k);
+            g.delta = genesis.delta.unwrap_or(g.delta);
+            g.slots_per_epoch = genesis.slots_per_epoch.unwrap_or(g.slots_per_epoch);
+            g.slots_per_sub_window = genesis
+                .slots_per_sub_window
+                .unwrap_or(g.slots_per_sub_window);
+            if let Some(timestamp) = &genesis.genesis_state_timestamp {
+                let timestamp = chrono::DateTime::parse_from_rfc3339(timestamp)
+                    .map_err(|err| Error::RuntimeConfig(format!("{timestamp}: {err}")))?;
+                g.genesis_state_timestamp = timestamp.timestamp_millis();
+            }
+        }
+        if let Some(txpool_max_size) = config.daemon.and_then(|d| d.txpool_max_size) {
+            self.genesis_constants.txpool_max_size = txpool_max_size;
+        }
+
+        Ok(self)
+    }
+
+    /// The preset with this name, otherwise the network from the file at this path.
+    pub fn select(name_or_path: &str) -> Result<Self, Error> {
+        match Self::preset(name_or_path) {
+            Some(network) => Ok(network),
+            None => Self::load(name_or_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_custom() {
+        let dir = std::env::temp_dir().join(format!("network-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut local = Network::berkeley();
+        local.name = "local".to_owned();
+        local.seeds = vec!["/ip4/127.0.0.1/tcp/8302".parse().unwrap()];
+
+        let json = dir.join("local.json");
+        fs::write(&json, serde_json::to_string(&local).unwrap()).unwrap();
+        let toml = dir.join("local.toml");
+        fs::write(&toml, toml::to_string(&local).unwrap()).unwrap();
+
+        for path in [json, toml] {
+            let loaded = Network::select(path.to_str().unwrap()).unwrap();
+            assert_eq!(loaded.name, "local");
+            assert_eq!(loaded.seeds, local.seeds);
+            assert_eq!(loaded.gossip_topic, GOSSIP_TOPIC);
+            assert_eq!(loaded.active_slots_coefficient, ACTIVE_SLOTS_COEFFICIENT);
+            assert_eq!(
+                loaded.genesis_constants.hash(),
+                local.genesis_constants.hash()
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+
+        for name in PRESETS {
+            let network = Network::select(name).unwrap();
+            assert_eq!(network.name, name);
+            assert_eq!(network.constraint_constants.ledger_depth, 35);
+        }
+    }
+
+    /// The published chain id of the mainnet after the Berkeley hard fork.
+    #[test]
+    fn mainnet_preset() {
+        let mainnet = Network::mainnet();
+        assert_eq!(
+            mainnet.chain_id,
+            "a7351abc7ddf2ea92d1b38cc8e636c271c1dfd2c081c637f62ebc2af34eb7cc1",
+        );
+        let fork = mainnet.constraint_constants.fork.unwrap();
+        assert_eq!(fork.previous_global_slot, 564480);
+        assert!(Network::devnet().constraint_constants.fork.is_some());
+    }
+
+    #[test]
+    fn runtime_config() {
+        let path = std::env::temp_dir().join(format!("runtime-config-{}.json", std::process::id()));
+        let config = serde_json::json!({
+            "genesis": {
+                "k": 20,
+                "slots_per_epoch": 480,
+                "genesis_state_timestamp": "2024-02-02T14:01:01Z"
+            },
+            "proof": {
+                "level": "full",
+                "work_delay": 1,
+                "transaction_capacity": { "2_to_the": 5 },
+                "coinbase_amount": "500",
+                "account_creation_fee": "0.1",
+                "fork": {
+                    "previous_state_hash": "3NKSiqFZQmAS12U8qeX4KNo8b4199spwNh7mrSs4Ci1Vacpfix2Q",
+                    "previous_length": 296371,
+                    "previous_global_slot": 445860
+                }
+            },
+            "ledger": { "accounts": [] }
+        });
+        fs::write(&path, config.to_string()).unwrap();
+
+        let network = Network::berkeley().with_runtime_config(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let c = &network.constraint_constants;
+        assert_eq!(c.work_delay, 1);
+        assert_eq!(c.transaction_capacity_log_2, 5);
+        assert_eq!(c.coinbase_amount, 500_000_000_000);
+        assert_eq!(c.account_creation_fee, 100_000_000);
+        assert_eq!(c.ledger_depth, BERKELEY_CONSTRAINT_CONSTANTS.ledger_depth);
+        assert_eq!(c.fork.as_ref().unwrap().previous_length, 296371);
+        let g = &network.genesis_constants;
+        assert_eq!((g.k, g.slots_per_epoch, g.delta), (20, 480, 0));
+        assert_eq!(g.genesis_state_timestamp, 1706882461000);
+
+        assert!(parse_mina("1.0000000001").is_err());
+        assert_eq!(parse_mina("0.000000001").unwrap(), 1);
+    }
+}